@@ -6,12 +6,12 @@
 ///
 /// Key Components:
 /// - Cli struct: Main entry point for CLI parsing
-/// - Commands enum: Available commands (list, copy, sync, mount)
+/// - Commands enum: Available commands (list, copy, sync, remove, mount)
 /// - Command-specific structs: Arguments for each command
 ///
 /// The module follows a hierarchical structure:
-/// 1. Global options (config file, verbosity)
-/// 2. Subcommands (list, copy, sync, mount)
+/// 1. Global options (config file, verbosity, credentials profile)
+/// 2. Subcommands (list, copy, sync, remove, mount)
 /// 3. Command-specific options
 ///
 /// Usage:
@@ -19,6 +19,9 @@
 /// and uses pattern matching on Commands to execute the appropriate action.
 
 use clap::{Parser, Subcommand};
+use crate::storage::bisync::ConflictPolicy;
+use crate::storage::compare::Compare;
+use crate::storage::plan::PlanFormat;
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -35,19 +38,51 @@ pub struct Cli {
     /// Verbose output
     #[arg(short, long, global = true)]
     pub verbose: bool,
+
+    /// AWS profile to take credentials from (overrides the config file)
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     /// Copy files between local and cloud storage
     Copy {
-        /// Source path (local path or s3:// URL)
+        /// Source path (local path, s3://, gs://, az://, file:// or mem:// URL)
         source: String,
-        /// Destination path (local path or s3:// URL)
+        /// Destination path (local path, s3://, gs://, az://, file:// or mem:// URL)
         destination: String,
         /// Recursively copy directories
         #[arg(short, long)]
         recursive: bool,
+
+        /// Only transfer files matching GLOB (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Skip files matching GLOB (repeatable); excludes win over includes
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Read filter rules from FILE: `+ GLOB` includes, `- GLOB` excludes
+        #[arg(long, value_name = "FILE")]
+        filter_from: Option<PathBuf>,
+
+        /// Print what the copy would do without transferring or deleting anything
+        #[arg(long, conflicts_with_all = ["resume", "restart"])]
+        dry_run: bool,
+
+        /// How --dry-run prints the plan
+        #[arg(long, value_enum, default_value_t = PlanFormat::Table, requires = "dry_run")]
+        plan_format: PlanFormat,
+
+        /// Continue an interrupted copy from its journal (fails if there is none)
+        #[arg(long, conflicts_with = "restart")]
+        resume: bool,
+
+        /// Discard the journal of an interrupted copy and start over
+        #[arg(long)]
+        restart: bool,
     },
 
     /// Mount cloud storage as local filesystem
@@ -76,16 +111,115 @@ pub enum Commands {
         /// Delete files in destination that don't exist in source
         #[arg(short = 'D', long)]
         delete: bool,
+
+        /// Abort instead of deleting more than N files
+        #[arg(long, value_name = "N")]
+        max_delete: Option<usize>,
+
+        /// Abort instead of deleting more than PERCENT of the destination's files
+        #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(..=100))]
+        max_delete_percent: Option<u8>,
+
+        /// Move files that would be deleted or overwritten under DIR: a key
+        /// prefix of the destination remote, or a directory for local destinations
+        #[arg(long, value_name = "DIR")]
+        backup_dir: Option<String>,
+
+        /// Propagate changes and deletes in both directions between a local
+        /// directory and a remote, against the state of the previous run
+        #[arg(long, conflicts_with_all = ["delete", "backup_dir"])]
+        bidirectional: bool,
+
+        /// Keep running after the sync, uploading local files as they change,
+        /// until interrupted
+        #[arg(long, conflicts_with_all = ["bidirectional", "dry_run"])]
+        watch: bool,
+
+        /// Seconds a file must go unchanged before --watch uploads it
+        #[arg(long, value_name = "SECONDS", default_value_t = 5.0, requires = "watch")]
+        debounce: f64,
+
+        /// How --bidirectional resolves files changed on both sides
+        #[arg(long, value_enum, default_value_t = ConflictPolicy::KeepBoth, requires = "bidirectional")]
+        conflict: ConflictPolicy,
+
+        /// Where --bidirectional keeps the state of the last run (defaults to
+        /// a file in the user data directory named after both locations)
+        #[arg(long, value_name = "FILE", requires = "bidirectional")]
+        baseline: Option<PathBuf>,
+
+        /// How to tell whether files present on both sides differ
+        #[arg(long, value_enum, default_value_t = Compare::Mtime)]
+        compare: Compare,
+
+        /// Only transfer files matching GLOB (repeatable)
+        #[arg(long, value_name = "GLOB")]
+        include: Vec<String>,
+
+        /// Skip files matching GLOB (repeatable); excludes win over includes
+        #[arg(long, value_name = "GLOB")]
+        exclude: Vec<String>,
+
+        /// Read filter rules from FILE: `+ GLOB` includes, `- GLOB` excludes
+        #[arg(long, value_name = "FILE")]
+        filter_from: Option<PathBuf>,
+
+        /// Print what the sync would do without transferring or deleting anything
+        #[arg(long, conflicts_with_all = ["resume", "restart"])]
+        dry_run: bool,
+
+        /// How --dry-run prints the plan
+        #[arg(long, value_enum, default_value_t = PlanFormat::Table, requires = "dry_run")]
+        plan_format: PlanFormat,
+
+        /// Continue an interrupted sync from its journal (fails if there is none)
+        #[arg(long, conflicts_with = "restart")]
+        resume: bool,
+
+        /// Discard the journal of an interrupted sync and start over
+        #[arg(long)]
+        restart: bool,
     },
 
     /// List files in a directory
     List {
-        /// Path to list (local path or s3:// URL)
+        /// Path to list (local path, s3://, gs://, az://, file:// or mem:// URL)
         path: String,
         /// Use long listing format
         #[arg(short, long)]
         long: bool,
     },
+
+    /// Delete objects from cloud storage
+    #[command(alias = "rm")]
+    Remove {
+        /// Object to delete, or prefix with --recursive (s3://, gs://, az://, file:// or mem:// URL)
+        path: String,
+
+        /// Delete every object under the prefix, in batches where the provider supports it
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Only delete objects matching GLOB (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        include: Vec<String>,
+
+        /// Keep objects matching GLOB (repeatable); excludes win over includes
+        #[arg(long, value_name = "GLOB", requires = "recursive")]
+        exclude: Vec<String>,
+
+        /// Read filter rules from FILE: `+ GLOB` includes, `- GLOB` excludes
+        #[arg(long, value_name = "FILE", requires = "recursive")]
+        filter_from: Option<PathBuf>,
+
+        /// Print what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// How --dry-run prints the plan
+        #[arg(long, value_enum, default_value_t = PlanFormat::Table, requires = "dry_run")]
+        plan_format: PlanFormat,
+    },
 }
 
 impl Cli {
@@ -6,34 +6,48 @@
 ///
 /// Configuration Structure:
 /// 1. Storage Configuration
-///    - Provider selection (S3)
-///    - Region and endpoint settings
+///    - Provider selection (S3, GCS, Azure, local filesystem, in-memory)
+///    - Region and endpoint settings (S3-compatible stores, path-style)
+///    - TLS settings (insecure mode)
 ///    - Bucket configuration
+///    - AWS credentials (static keys, session token, profile)
+///    - Provider credentials (GCS service accounts, Azure keys/SAS)
+///    - Fault injection for testing against unreliable storage
 ///
-/// 2. Mount Options
+/// 2. Remotes
+///    - Named storage configurations (`remotes`), addressed as `name:key`
+///    - Resolution of parsed storage URLs to the remote that serves them
+///
+/// 3. Mount Options
 ///    - Cache size and timeout settings
 ///    - Read-only mode configuration
 ///
-/// 3. Transfer Options
-///    - Concurrent transfer limits
+/// 4. Transfer Options
+///    - Concurrent transfer limits (files and parts per file)
 ///    - Chunk size configuration
-///    - Retry settings
+///    - Retry settings (attempts, backoff, per-operation timeouts)
+///    - Transfer journal location
 ///
 /// The configuration can be loaded from a file or environment variables,
 /// with sensible defaults provided when no configuration is specified.
 
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use crate::error::{Result, ToolError};
+use crate::storage::url::{Authority, RemoteUrl};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub default_storage: StorageConfig,
+    /// Named remotes, addressed as `name:key`
+    #[serde(default)]
+    pub remotes: HashMap<String, StorageConfig>,
     pub mount_options: MountOptions,
     pub transfer_options: TransferOptions,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct StorageConfig {
     pub provider: StorageProvider,
     pub region: Option<String>,
@@ -41,16 +55,101 @@ pub struct StorageConfig {
     pub bucket: Option<String>,
     pub access_key_id: Option<String>,
     pub secret_access_key: Option<String>,
+    /// Session token for temporary access keys
+    pub session_token: Option<String>,
+    /// AWS shared config/credentials profile to use exclusively
+    pub profile: Option<String>,
+    /// GCS service-account JSON key file
+    pub service_account_path: Option<PathBuf>,
+    /// GCS service-account JSON key, inline
+    pub service_account_key: Option<String>,
+    /// Azure storage account name
+    pub account_name: Option<String>,
+    /// Azure storage account key
+    pub account_key: Option<String>,
+    /// Azure shared access signature (query string form)
+    pub sas_token: Option<String>,
+    /// Use path-style S3 requests (defaults to true when `endpoint` is set)
+    pub force_path_style: Option<bool>,
+    /// Send CRC32C checksums with S3 uploads (defaults to true; disable for
+    /// S3-compatible stores that reject them)
+    pub send_checksums: Option<bool>,
+    /// PEM file of extra root certificates to trust, for endpoints behind a
+    /// private certificate authority
+    pub ca_bundle: Option<PathBuf>,
+    /// Disable TLS certificate verification (local testing only)
+    #[serde(default)]
+    pub allow_insecure: bool,
+    /// Inject latency and failures into every request (testing only)
+    pub fault_injection: Option<FaultInjection>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub enum StorageProvider {
+    #[default]
     #[serde(rename = "s3")]
     S3,
     #[serde(rename = "gcs")]
     GCS,
     #[serde(rename = "azure")]
     Azure,
+    /// Local or network filesystem; `bucket` is the root directory
+    #[serde(rename = "file")]
+    Local,
+    /// Process-local in-memory store; contents are lost on exit
+    #[serde(rename = "memory")]
+    Memory,
+}
+
+impl StorageProvider {
+    /// Provider addressed by a storage URL scheme (without `://`)
+    pub fn from_scheme(scheme: &str) -> Option<Self> {
+        match scheme {
+            "s3" => Some(StorageProvider::S3),
+            "gs" => Some(StorageProvider::GCS),
+            "az" => Some(StorageProvider::Azure),
+            "file" => Some(StorageProvider::Local),
+            "mem" => Some(StorageProvider::Memory),
+            _ => None,
+        }
+    }
+
+    /// URL scheme (without `://`) addressing this provider
+    pub fn scheme(&self) -> &'static str {
+        match self {
+            StorageProvider::S3 => "s3",
+            StorageProvider::GCS => "gs",
+            StorageProvider::Azure => "az",
+            StorageProvider::Local => "file",
+            StorageProvider::Memory => "mem",
+        }
+    }
+}
+
+/// A remote URL resolved to the remote that serves it
+#[derive(Debug, Clone)]
+pub struct Remote {
+    /// Remote name, or `scheme://bucket` for URLs not served by a named remote
+    pub name: String,
+    pub config: StorageConfig,
+    /// Object key within the remote's bucket
+    pub key: String,
+}
+
+/// Faults injected into storage requests; probabilities are per request, 0.0-1.0
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultInjection {
+    /// Delay added before every request
+    pub latency_ms: u64,
+    /// Chance of a `503 SlowDown` throttling error
+    pub throttle_probability: f64,
+    /// Chance of a generic transient failure
+    pub failure_probability: f64,
+    /// Chance that a download stream is cut off part-way
+    pub truncate_probability: f64,
+    /// RNG seed, for reproducible fault sequences
+    pub seed: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,11 +159,52 @@ pub struct MountOptions {
     pub read_only: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferOptions {
+    /// Files transferred at once by directory copies and syncs
     pub concurrent_uploads: usize,
+    /// Parts or ranges of a single file transferred at once
+    #[serde(default = "default_concurrent_parts")]
+    pub concurrent_parts: usize,
+    /// Bytes per multipart upload part / streamed chunk
     pub chunk_size: usize,
+    /// Retries after the first attempt of a failed storage call
     pub retry_attempts: u32,
+    /// First retry delay; doubles per retry
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+    /// Cap on the retry delay
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub retry_max_delay_ms: u64,
+    /// Per-attempt timeout of metadata requests (0 disables)
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Per-attempt timeout of data transfers (0 disables)
+    #[serde(default = "default_transfer_timeout_secs")]
+    pub transfer_timeout_secs: u64,
+    /// Where transfer journals are kept (defaults to the user data directory)
+    #[serde(default)]
+    pub journal_dir: Option<PathBuf>,
+}
+
+fn default_concurrent_parts() -> usize {
+    4
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    200
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    20_000
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_transfer_timeout_secs() -> u64 {
+    600
 }
 
 impl Config {
@@ -88,6 +228,79 @@ impl Config {
             .map_err(|e| ToolError::Config(format!("Failed to parse config file: {}", e)))
     }
 
+    /// Resolve a parsed remote URL to the remote that serves it.
+    ///
+    /// - `name:key` addresses the remote `name` from `remotes`
+    /// - `scheme://bucket/key` uses the named remote with that provider and
+    ///   bucket if there is one, else `default_storage` if its provider
+    ///   matches, else the provider's defaults; the bucket always comes from
+    ///   the URL
+    /// - `file:///path` is rooted at `/`, or at `default_storage.bucket`
+    ///   when the default provider is the local filesystem
+    pub fn resolve(&self, url: &RemoteUrl) -> Result<Remote> {
+        match &url.authority {
+            Authority::Named(name) => {
+                let config = self.remotes.get(name).ok_or_else(|| {
+                    let mut names: Vec<&str> = self.remotes.keys().map(|name| name.as_str()).collect();
+                    names.sort();
+                    ToolError::Config(format!(
+                        "Unknown remote '{}' in {} (configured remotes: {}); write ./{} for a local path",
+                        name,
+                        url,
+                        if names.is_empty() { "none".to_string() } else { names.join(", ") },
+                        url
+                    ))
+                })?;
+                Ok(Remote { name: name.clone(), config: config.clone(), key: url.key.clone() })
+            }
+            Authority::Filesystem if self.default_storage.provider == StorageProvider::Local => {
+                let root = PathBuf::from(self.default_storage.bucket.as_deref().unwrap_or("/"));
+                let path = PathBuf::from("/").join(&url.key);
+                let key = path.strip_prefix(&root)
+                    .map_err(|_| ToolError::Config(format!(
+                        "{} is outside the local storage root {}",
+                        url,
+                        root.display()
+                    )))?;
+                Ok(Remote {
+                    name: format!("file://{}", root.display()),
+                    config: self.default_storage.clone(),
+                    key: key.to_string_lossy().to_string(),
+                })
+            }
+            Authority::Filesystem => Ok(Remote {
+                name: "file://".to_string(),
+                config: StorageConfig {
+                    provider: StorageProvider::Local,
+                    fault_injection: self.default_storage.fault_injection.clone(),
+                    ..Default::default()
+                },
+                key: url.key.clone(),
+            }),
+            Authority::Bucket { provider, bucket } => {
+                let named = self.remotes.iter().find(|(_, remote)| {
+                    &remote.provider == provider && remote.bucket.as_deref() == Some(bucket.as_str())
+                });
+                let name = format!("{}://{}", provider.scheme(), bucket);
+                let (name, mut config) = match named {
+                    Some((name, remote)) => (name.clone(), remote.clone()),
+                    None if &self.default_storage.provider == provider => (name, self.default_storage.clone()),
+                    None => (
+                        name,
+                        StorageConfig {
+                            provider: provider.clone(),
+                            fault_injection: self.default_storage.fault_injection.clone(),
+                            ..Default::default()
+                        },
+                    ),
+                };
+                config.bucket = Some(bucket.clone());
+
+                Ok(Remote { name, config, key: url.key.clone() })
+            }
+        }
+    }
+
     pub fn save(&self, path: Option<PathBuf>) -> Result<()> {
         let config_path = path.or_else(|| {
             dirs::config_dir().map(|mut p| {
@@ -117,11 +330,9 @@ impl Default for Config {
             default_storage: StorageConfig {
                 provider: StorageProvider::S3,
                 region: Some("us-east-1".to_string()),
-                endpoint: None,
-                bucket: None,
-                access_key_id: None,
-                secret_access_key: None,
+                ..Default::default()
             },
+            remotes: HashMap::new(),
             mount_options: MountOptions {
                 cache_size_mb: 1024,
                 timeout_seconds: 300,
@@ -129,8 +340,14 @@ impl Default for Config {
             },
             transfer_options: TransferOptions {
                 concurrent_uploads: 4,
+                concurrent_parts: default_concurrent_parts(),
                 chunk_size: 8 * 1024 * 1024, // 8MB
                 retry_attempts: 3,
+                retry_base_delay_ms: default_retry_base_delay_ms(),
+                retry_max_delay_ms: default_retry_max_delay_ms(),
+                request_timeout_secs: default_request_timeout_secs(),
+                transfer_timeout_secs: default_transfer_timeout_secs(),
+                journal_dir: None,
             },
         }
     }
@@ -6,10 +6,13 @@
 ///
 /// Error Types:
 /// - Config: Configuration-related errors
+/// - Credentials: No usable cloud credentials could be resolved
 /// - Io: File system operation errors
 /// - Storage: Cloud storage errors (S3)
-/// - NotImplemented: Features not yet available
 /// - InvalidOperation: User input validation errors
+/// - Transfers: Failures of individual files in a batch transfer
+/// - ChecksumMismatch: Downloaded or copied content differs from the checksum stored
+///   with the object
 ///
 /// The module provides:
 /// 1. Custom Result type alias for consistent error handling
@@ -29,20 +32,34 @@ pub type Result<T> = std::result::Result<T, ToolError>;
 #[derive(Debug)]
 pub enum ToolError {
     Config(String),
+    Credentials(String),
     Io(std::io::Error),
     Storage(object_store::Error),
-    NotImplemented(String),
     InvalidOperation(String),
+    /// Every failed file of a batch, keyed by path
+    Transfers { total: usize, failures: Vec<(String, ToolError)> },
+    /// Content of `path` hashed to `actual` instead of `expected`
+    ChecksumMismatch { path: String, algorithm: &'static str, expected: String, actual: String },
 }
 
 impl fmt::Display for ToolError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ToolError::Config(msg) => write!(f, "Configuration error: {}", msg),
+            ToolError::Credentials(msg) => write!(f, "Credential error: {}", msg),
             ToolError::Io(err) => write!(f, "I/O error: {}", err),
             ToolError::Storage(err) => write!(f, "Storage error: {}", err),
-            ToolError::NotImplemented(feature) => write!(f, "Feature not implemented: {}", feature),
             ToolError::InvalidOperation(msg) => write!(f, "Invalid operation: {}", msg),
+            ToolError::Transfers { total, failures } => {
+                write!(f, "{} of {} transfers failed:", failures.len(), total)?;
+                for (path, err) in failures {
+                    write!(f, "\n  - {}: {}", path, err)?;
+                }
+                Ok(())
+            }
+            ToolError::ChecksumMismatch { path, algorithm, expected, actual } => {
+                write!(f, "Checksum mismatch for {}: expected {} {}, got {}", path, algorithm, expected, actual)
+            }
         }
     }
 }
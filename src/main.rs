@@ -28,107 +28,341 @@ mod storage;
 // mod fuse;
 
 use cli::{Cli, Commands};
-use config::Config;
+use config::{Config, StorageProvider};
 use error::{Result, ToolError};
-use storage::s3::S3Storage;
+use std::sync::Arc;
+use storage::{bisync, build_backend, SyncOptions, TransferContext};
+use storage::filter::Filter;
+use storage::journal::{self, Journal, Resume};
+use storage::plan::{Action, Plan};
+use storage::url::{RemoteUrl, StorageUrl};
 // use fuse::CloudFS;
 
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logging
-    tracing_subscriber::fmt::init();
+fn main() -> Result<()> {
+    // Initialize logging; logs go to stderr so stdout carries only output
+    // such as listings and dry-run plans
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
 
     // Parse command line arguments
     let cli = Cli::parse_args();
 
     // Load configuration
-    let config = Config::load(cli.config)?;
+    let mut config = Config::load(cli.config)?;
+    if let Some(profile) = cli.profile {
+        config.default_storage.profile = Some(profile.clone());
+        for remote in config.remotes.values_mut() {
+            if remote.provider == StorageProvider::S3 {
+                remote.profile = Some(profile.clone());
+            }
+        }
+    }
+
+    // Set up before the runtime starts, as this changes the environment
+    storage::azure::set_emulator_endpoint(&config)?;
+    storage::tls::set_ca_bundle(&config)?;
+
+    // Dry runs record what they would do in a plan and print it at the end
+    let plan = match &cli.command {
+        Commands::Copy { dry_run: true, plan_format, .. }
+        | Commands::Sync { dry_run: true, plan_format, .. }
+        | Commands::Remove { dry_run: true, plan_format, .. } => {
+            Some((Arc::new(Plan::new()), *plan_format))
+        }
+        _ => None,
+    };
+
+    // Copies and syncs record their progress so an interrupted run resumes;
+    // dry runs transfer nothing, so they leave journals alone
+    let journal = match &cli.command {
+        Commands::Copy { source, destination, resume, restart, dry_run: false, .. } => {
+            open_journal(&config, "copy", source, destination, *resume, *restart)?
+        }
+        Commands::Sync { source, destination, resume, restart, dry_run: false, .. } => {
+            open_journal(&config, "sync", source, destination, *resume, *restart)?
+        }
+        _ => None,
+    };
+
+    // Include/exclude filters select the files of recursive copies, syncs
+    // and removes
+    let filter = match &cli.command {
+        Commands::Copy { include, exclude, filter_from, .. }
+        | Commands::Sync { include, exclude, filter_from, .. }
+        | Commands::Remove { include, exclude, filter_from, .. } => {
+            Filter::new(include, exclude, filter_from.as_deref())?
+        }
+        _ => None,
+    };
 
-    // Initialize storage backend
-    let storage = S3Storage::new(&config.default_storage).await?;
+    let context = TransferContext {
+        journal: journal.clone(),
+        filter: filter.map(Arc::new),
+        plan: plan.as_ref().map(|(plan, _)| plan.clone()),
+    };
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(run_command(cli.command, &config, &context));
+    if let Some(journal) = journal {
+        match &result {
+            Ok(()) => {
+                if let Err(e) = journal.finish() {
+                    error!("Could not remove journal {}: {}", journal.path().display(), e);
+                }
+            }
+            Err(_) => error!(
+                "Progress was saved to {}; rerun the same command to resume",
+                journal.path().display()
+            ),
+        }
+    }
+    if let (Ok(()), Some((plan, format))) = (&result, plan) {
+        plan.print(format)?;
+    }
+    result
+}
+
+/// Open the journal of a copy or sync. It is named after the command and
+/// both locations, with local paths made absolute, so rerunning the same
+/// command from anywhere finds it. Unless `--resume` asked for it, a journal
+/// that cannot be opened only costs the ability to resume, so the command
+/// runs without one.
+fn open_journal(config: &Config, command: &str, source: &str, destination: &str, resume: bool, restart: bool) -> Result<Option<Arc<Journal>>> {
+    let location = |path: &str| -> Result<String> {
+        Ok(match path.parse::<StorageUrl>()? {
+            StorageUrl::Local(path) => std::path::absolute(&path)?.display().to_string(),
+            StorageUrl::Remote(url) => url.to_string(),
+        })
+    };
+    let operation = format!("{} {} {}", command, location(source)?, location(destination)?);
 
-    match cli.command {
-        Commands::Copy { source, destination, recursive } => {
+    let resume = if restart {
+        Resume::Restart
+    } else if resume {
+        Resume::Required
+    } else {
+        Resume::Auto
+    };
+    let journal = config.transfer_options.journal_dir.clone()
+        .or_else(journal::default_dir)
+        .ok_or_else(|| ToolError::Config("Could not determine journal directory".into()))
+        .and_then(|dir| Journal::open(&dir, &operation, resume));
+    match journal {
+        Ok(journal) => Ok(Some(Arc::new(journal))),
+        Err(e) if resume != Resume::Required => {
+            warn!("Continuing without a journal, so an interrupted {} cannot be resumed: {}", command, e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn run_command(command: Commands, config: &Config, context: &TransferContext) -> Result<()> {
+    // Each command builds a client per remote it touches, so source and
+    // destination can live in different buckets, accounts or providers
+    match command {
+        Commands::Copy { source, destination, recursive, .. } => {
             info!("Copying {} to {}", source, destination);
-            
-            let is_source_cloud = source.starts_with("s3://");
-            let is_dest_cloud = destination.starts_with("s3://");
 
-            match (is_source_cloud, is_dest_cloud) {
+            match (source.parse::<StorageUrl>()?, destination.parse::<StorageUrl>()?) {
                 // Local to cloud
-                (false, true) => {
-                    let local_path = std::path::Path::new(&source);
-                    let remote_path = destination.trim_start_matches("s3://")
-                        .trim_start_matches(&config.default_storage.bucket.unwrap_or_default())
-                        .trim_start_matches('/');
+                (StorageUrl::Local(local_path), StorageUrl::Remote(dest_url)) => {
+                    let upload_dir = recursive && local_path.is_dir();
+
+                    // A single file copied into a directory keeps its name
+                    let dest_url = match local_path.file_name() {
+                        Some(name) if !upload_dir => RemoteUrl {
+                            key: dest_url.key_for(&name.to_string_lossy()),
+                            trailing_slash: false,
+                            ..dest_url
+                        },
+                        _ => dest_url,
+                    };
+                    let dest = config.resolve(&dest_url)?;
+                    let storage = build_backend(&dest.config, &config.transfer_options, context).await?;
                     
-                    if recursive && local_path.is_dir() {
-                        storage.upload_directory(local_path, remote_path).await?;
+                    if upload_dir {
+                        storage.upload_directory(&local_path, &dest.key).await?;
+                    } else if let Some(plan) = &context.plan {
+                        let size = std::fs::metadata(&local_path)?.len();
+                        plan.add(Action::Upload, dest.key, Some(size), "copy overwrites without comparing");
                     } else {
-                        storage.upload_file(local_path, remote_path).await?;
+                        storage.upload_file(&local_path, &dest.key).await?;
                     }
                 }
                 // Cloud to local
-                (true, false) => {
-                    let remote_path = source.trim_start_matches("s3://")
-                        .trim_start_matches(&config.default_storage.bucket.unwrap_or_default())
-                        .trim_start_matches('/');
-                    let local_path = std::path::Path::new(&destination);
+                (StorageUrl::Remote(src_url), StorageUrl::Local(local_path)) => {
+                    let src = config.resolve(&src_url)?;
+                    let storage = build_backend(&src.config, &config.transfer_options, context).await?;
                     
                     if recursive {
-                        storage.download_directory(remote_path, local_path).await?;
+                        storage.download_directory(&src.key, &local_path).await?;
+                        return Ok(());
+                    }
+
+                    let local_path = if destination.ends_with('/') || local_path.is_dir() {
+                        local_path.join(src_url.file_name())
+                    } else {
+                        local_path
+                    };
+                    if let Some(plan) = &context.plan {
+                        let size = storage.object_size(&src.key).await?;
+                        plan.add(Action::Download, local_path.display().to_string(), Some(size), "copy overwrites without comparing");
                     } else {
-                        storage.download_file(remote_path, local_path).await?;
+                        storage.download_file(&src.key, &local_path).await?;
                     }
                 }
                 // Cloud to cloud
-                (true, true) => {
-                    error!("Cloud to cloud copy not yet implemented");
-                    return Err(ToolError::NotImplemented("Cloud to cloud copy".into()));
+                (StorageUrl::Remote(src_url), StorageUrl::Remote(dest_url)) => {
+                    if !recursive && src_url.key.is_empty() {
+                        return Err(ToolError::InvalidOperation(format!("{} is a bucket; use --recursive to copy its contents", src_url)));
+                    }
+
+                    // A single object copied into a directory keeps its name
+                    let dest_url = if recursive {
+                        dest_url
+                    } else {
+                        RemoteUrl {
+                            key: dest_url.key_for(src_url.file_name()),
+                            trailing_slash: false,
+                            ..dest_url
+                        }
+                    };
+                    let src = config.resolve(&src_url)?;
+                    let dest = config.resolve(&dest_url)?;
+
+                    // Copies within one remote share a client, so the store
+                    // can copy server-side; S3 also does so across buckets
+                    // reached with the same credentials
+                    let source_storage = build_backend(&src.config, &config.transfer_options, context).await?;
+                    let other_storage = if src.name == dest.name {
+                        None
+                    } else {
+                        Some(build_backend(&dest.config, &config.transfer_options, context).await?)
+                    };
+                    let dest_storage = other_storage.as_deref().unwrap_or(source_storage.as_ref());
+
+                    if recursive {
+                        dest_storage.copy_directory_from(source_storage.as_ref(), &src.key, &dest.key).await?;
+                    } else if let Some(plan) = &context.plan {
+                        let size = source_storage.object_size(&src.key).await?;
+                        plan.add(Action::Copy, dest.key, Some(size), "copy overwrites without comparing");
+                    } else {
+                        dest_storage.copy_from(source_storage.as_ref(), &src.key, &dest.key).await?;
+                    }
                 }
                 // Local to local
-                (false, false) => {
-                    error!("Local to local copy should use system commands");
-                    return Err(ToolError::InvalidOperation("Use system commands for local copy".into()));
+                (StorageUrl::Local(_), StorageUrl::Local(_)) => {
+                    error!("Local to local copy needs a file:// URL on one side");
+                    return Err(ToolError::InvalidOperation("Use a file:// URL to copy between local paths".into()));
                 }
             }
         }
 
         Commands::Mount { source, mountpoint, readonly } => {
             info!("Mounting {} at {}", source, mountpoint.display());
-            
-            // Extract bucket and path from s3:// URL
-            let bucket = source.trim_start_matches("s3://")
-                .split('/')
-                .next()
-                .ok_or_else(|| ToolError::InvalidOperation("Invalid S3 URL format".into()))?;
-            
-            let path = source.trim_start_matches("s3://")
-                .trim_start_matches(bucket)
-                .trim_start_matches('/');
-
-            // Build mount-s3 command
-            let mut command = std::process::Command::new("mount-s3");
-            command.arg(bucket);
-            command.arg(mountpoint.clone());
-
-            // Add options
-            if readonly {
-                command.arg("--read-only");
-            }
 
-            // Use AWS credentials from config if available
-            if let Some(key_id) = config.default_storage.access_key_id {
-                command.env("AWS_ACCESS_KEY_ID", key_id);
-            }
-            if let Some(secret_key) = config.default_storage.secret_access_key {
-                command.env("AWS_SECRET_ACCESS_KEY", secret_key);
-            }
-            if let Some(region) = config.default_storage.region {
-                command.env("AWS_DEFAULT_REGION", region);
-            }
+            let url = match source.parse::<StorageUrl>()? {
+                StorageUrl::Remote(url) => url,
+                StorageUrl::Local(_) => {
+                    return Err(ToolError::InvalidOperation(format!("{} is not a storage URL or remote", source)));
+                }
+            };
+            let remote = config.resolve(&url)?;
+            let storage = remote.config;
+            let path = remote.key.as_str();
+            let bucket = match storage.provider {
+                StorageProvider::Local => {
+                    return Err(ToolError::InvalidOperation("file:// URLs are already on the local filesystem".into()));
+                }
+                StorageProvider::Memory => {
+                    return Err(ToolError::InvalidOperation("mem:// storage cannot be mounted".into()));
+                }
+                _ => storage.bucket.clone()
+                    .ok_or_else(|| ToolError::Config(format!("Remote {} has no bucket configured", remote.name)))?,
+            };
+
+            let (tool, provider, mut command) = if storage.provider == StorageProvider::GCS {
+                // Build gcsfuse command
+                let mut command = std::process::Command::new("gcsfuse");
+                if !path.is_empty() {
+                    command.arg("--only-dir").arg(path);
+                }
+                if readonly {
+                    command.args(["-o", "ro"]);
+                }
+                if let Some(key_file) = &storage.service_account_path {
+                    command.arg("--key-file").arg(key_file);
+                }
+                if let Some(endpoint) = &storage.endpoint {
+                    command.arg("--custom-endpoint").arg(endpoint);
+                }
+                command.arg(&bucket);
+                command.arg(mountpoint.clone());
+                ("gcsfuse", "GCS", command)
+            } else if storage.provider == StorageProvider::Azure {
+                // Build blobfuse2 command
+                let mut command = std::process::Command::new("blobfuse2");
+                command.arg("mount");
+                command.arg(mountpoint.clone());
+                command.arg(format!("--container-name={}", bucket));
+                if !path.is_empty() {
+                    command.arg(format!("--subdirectory={}", path));
+                }
+                if readonly {
+                    command.arg("--read-only");
+                }
+
+                // Pass account credentials through blobfuse2's environment configuration
+                if let Some(account) = &storage.account_name {
+                    command.env("AZURE_STORAGE_ACCOUNT", account);
+                }
+                if let Some(key) = &storage.account_key {
+                    command.env("AZURE_STORAGE_AUTH_TYPE", "key");
+                    command.env("AZURE_STORAGE_ACCESS_KEY", key);
+                } else if let Some(sas) = &storage.sas_token {
+                    command.env("AZURE_STORAGE_AUTH_TYPE", "sas");
+                    command.env("AZURE_STORAGE_SAS_TOKEN", sas);
+                }
+                if let Some(endpoint) = &storage.endpoint {
+                    command.env("AZURE_STORAGE_BLOB_ENDPOINT", endpoint);
+                }
+                ("blobfuse2", "Azure", command)
+            } else {
+                // Build mount-s3 command
+                let mut command = std::process::Command::new("mount-s3");
+                command.arg(&bucket);
+                command.arg(mountpoint.clone());
+
+                // Add options
+                if readonly {
+                    command.arg("--read-only");
+                }
+                if let Some(endpoint) = &storage.endpoint {
+                    command.arg("--endpoint-url").arg(endpoint);
+                }
+                if storage.force_path_style.unwrap_or(storage.endpoint.is_some()) {
+                    command.arg("--force-path-style");
+                }
+
+                // Use AWS credentials from config if available
+                if let Some(key_id) = storage.access_key_id {
+                    command.env("AWS_ACCESS_KEY_ID", key_id);
+                }
+                if let Some(secret_key) = storage.secret_access_key {
+                    command.env("AWS_SECRET_ACCESS_KEY", secret_key);
+                }
+                if let Some(token) = storage.session_token {
+                    command.env("AWS_SESSION_TOKEN", token);
+                }
+                if let Some(profile) = storage.profile {
+                    command.env("AWS_PROFILE", profile);
+                }
+                if let Some(region) = storage.region {
+                    command.env("AWS_DEFAULT_REGION", region);
+                }
+                ("mount-s3", "S3", command)
+            };
 
             // Execute mount command
             let status = command.status()
@@ -136,74 +370,128 @@ async fn main() -> Result<()> {
 
             if !status.success() {
                 return Err(ToolError::InvalidOperation(format!(
-                    "Failed to mount S3 bucket. Make sure mount-s3 is installed and you have the necessary permissions. Exit code: {}",
+                    "Failed to mount {} bucket. Make sure {} is installed and you have the necessary permissions. Exit code: {}",
+                    provider,
+                    tool,
                     status.code().unwrap_or(-1)
                 )));
             }
 
-            info!("Successfully mounted S3 bucket {} at {}", bucket, mountpoint.display());
+            info!("Successfully mounted {} bucket {} at {}", provider, bucket, mountpoint.display());
         }
 
-        Commands::Sync { source, destination, delete } => {
+        Commands::Sync { source, destination, delete, compare, max_delete, max_delete_percent, backup_dir, bidirectional, conflict, baseline, watch, debounce, .. } => {
             info!("Syncing {} to {}", source, destination);
-            
-            let is_source_cloud = source.starts_with("s3://");
-            let is_dest_cloud = destination.starts_with("s3://");
+            let options = SyncOptions { delete, compare, max_delete, max_delete_percent, backup_dir };
 
-            match (is_source_cloud, is_dest_cloud) {
+            if bidirectional {
+                let (local_dir, remote_url) = match (source.parse::<StorageUrl>()?, destination.parse::<StorageUrl>()?) {
+                    (StorageUrl::Local(local_dir), StorageUrl::Remote(url)) | (StorageUrl::Remote(url), StorageUrl::Local(local_dir)) => (local_dir, url),
+                    _ => {
+                        return Err(ToolError::InvalidOperation("--bidirectional needs a local directory on one side and a remote on the other".into()));
+                    }
+                };
+                let remote = config.resolve(&remote_url)?;
+                let storage = build_backend(&remote.config, &config.transfer_options, context).await?;
+
+                // Named after both locations, so either argument order finds it
+                let baseline = match baseline {
+                    Some(baseline) => baseline,
+                    None => {
+                        let operation = format!("{} {}", std::path::absolute(&local_dir)?.display(), remote_url);
+                        bisync::default_baseline(&operation)
+                            .ok_or_else(|| ToolError::Config("Could not determine baseline directory; pass --baseline".into()))?
+                    }
+                };
+                storage.sync_bidirectional(&local_dir, &remote.key, &baseline, conflict, &options).await?;
+                return Ok(());
+            }
+            if watch && !matches!((source.parse::<StorageUrl>()?, destination.parse::<StorageUrl>()?), (StorageUrl::Local(_), StorageUrl::Remote(_))) {
+                return Err(ToolError::InvalidOperation("--watch needs a local source directory and a remote destination".into()));
+            }
+
+            match (source.parse::<StorageUrl>()?, destination.parse::<StorageUrl>()?) {
                 // Cloud to cloud sync
-                (true, true) => {
-                    let bucket = config.default_storage.bucket.clone().unwrap_or_default();
-                    let source_path = source.trim_start_matches("s3://")
-                        .trim_start_matches(&bucket)
-                        .trim_start_matches('/');
-                    let dest_path = destination.trim_start_matches("s3://")
-                        .trim_start_matches(&bucket)
-                        .trim_start_matches('/');
-                    
-                    storage.sync_directories(source_path, dest_path, delete).await?;
+                (StorageUrl::Remote(src_url), StorageUrl::Remote(dest_url)) => {
+                    let src = config.resolve(&src_url)?;
+                    let dest = config.resolve(&dest_url)?;
+                    let source_storage = build_backend(&src.config, &config.transfer_options, context).await?;
+                    let other_storage = if src.name == dest.name {
+                        None
+                    } else {
+                        Some(build_backend(&dest.config, &config.transfer_options, context).await?)
+                    };
+                    let dest_storage = other_storage.as_deref().unwrap_or(source_storage.as_ref());
+
+                    dest_storage.sync_directories_from(source_storage.as_ref(), &src.key, &dest.key, &options).await?;
                 }
                 // Local to cloud sync
-                (false, true) => {
-                    let local_dir = std::path::Path::new(&source);
-                    let remote_prefix = destination.trim_start_matches("s3://")
-                        .trim_start_matches(&config.default_storage.bucket.unwrap_or_default())
-                        .trim_start_matches('/');
+                (StorageUrl::Local(local_dir), StorageUrl::Remote(dest_url)) => {
+                    let dest = config.resolve(&dest_url)?;
+                    let storage = build_backend(&dest.config, &config.transfer_options, context).await?;
                     
-                    storage.sync_local_to_remote(local_dir, remote_prefix, delete).await?;
+                    if watch {
+                        let debounce = std::time::Duration::try_from_secs_f64(debounce)
+                            .map_err(|_| ToolError::InvalidOperation(format!("Invalid --debounce {}", debounce)))?;
+                        storage.watch_local_to_remote(&local_dir, &dest.key, &options, debounce).await?;
+                    } else {
+                        storage.sync_local_to_remote(&local_dir, &dest.key, &options).await?;
+                    }
                 }
                 // Cloud to local sync
-                (true, false) => {
-                    let remote_prefix = source.trim_start_matches("s3://")
-                        .trim_start_matches(&config.default_storage.bucket.unwrap_or_default())
-                        .trim_start_matches('/');
-                    let local_dir = std::path::Path::new(&destination);
+                (StorageUrl::Remote(src_url), StorageUrl::Local(local_dir)) => {
+                    let src = config.resolve(&src_url)?;
+                    let storage = build_backend(&src.config, &config.transfer_options, context).await?;
                     
-                    storage.sync_remote_to_local(remote_prefix, local_dir, delete).await?;
+                    storage.sync_remote_to_local(&src.key, &local_dir, &options).await?;
                 }
                 // Local to local sync
-                (false, false) => {
-                    error!("Local to local sync should use system commands");
-                    return Err(ToolError::InvalidOperation("Use system commands for local sync".into()));
+                (StorageUrl::Local(_), StorageUrl::Local(_)) => {
+                    error!("Local to local sync needs a file:// URL on one side");
+                    return Err(ToolError::InvalidOperation("Use a file:// URL to sync between local paths".into()));
                 }
             }
         }
 
+        Commands::Remove { path, recursive, .. } => {
+            info!("Removing {}", path);
+            let url = match path.parse::<StorageUrl>()? {
+                StorageUrl::Remote(url) => url,
+                StorageUrl::Local(_) => {
+                    return Err(ToolError::InvalidOperation(format!("{} is a local path; use a storage URL or name:key", path)));
+                }
+            };
+            let remote = config.resolve(&url)?;
+            if !recursive && remote.key.is_empty() {
+                return Err(ToolError::InvalidOperation(format!("{} is a bucket; use --recursive to remove its contents", url)));
+            }
+
+            let storage = build_backend(&remote.config, &config.transfer_options, context).await?;
+            if recursive {
+                storage.remove_directory(&remote.key).await?;
+            } else if let Some(plan) = &context.plan {
+                let size = storage.object_size(&remote.key).await?;
+                plan.add(Action::Delete, remote.key, Some(size), "removed");
+            } else {
+                storage.delete_object(&remote.key).await?;
+            }
+        }
+
         Commands::List { path, long } => {
             info!("Listing contents of {}", path);
-            // Strip s3:// prefix and bucket name if present
-            let prefix = if path.starts_with("s3://") {
-                let without_scheme = path.trim_start_matches("s3://");
-                if let Some(rest) = without_scheme.find('/') {
-                    &without_scheme[rest + 1..]
-                } else {
-                    ""
+            let url = match path.parse::<StorageUrl>()? {
+                StorageUrl::Remote(url) => url,
+                StorageUrl::Local(_) => {
+                    return Err(ToolError::InvalidOperation(format!(
+                        "{} is a local path; use a storage URL or name:key (file:// for local directories)",
+                        path
+                    )));
                 }
-            } else {
-                &path
             };
-            
-            let objects = storage.list_objects(prefix).await?;
+            let remote = config.resolve(&url)?;
+
+            let storage = build_backend(&remote.config, &config.transfer_options, context).await?;
+            let objects = storage.list_objects(&remote.key).await?;
             for obj in objects {
                 if long {
                     // TODO: Add more details in long format
@@ -216,4 +504,4 @@ async fn main() -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,130 @@
+//! Azure Blob Storage Implementation Module
+//!
+//! This module builds an Azure Blob client on top of object_store's
+//! MicrosoftAzure store and plugs it into the StorageBackend trait. The
+//! configured `bucket` is the blob container.
+//!
+//! Credentials:
+//! - `account_name` + `account_key`: shared key authorization
+//! - `account_name` + `sas_token`: shared access signature
+//! - neither key nor SAS: managed identity (instance metadata service)
+//!
+//! Endpoint:
+//! When `endpoint` is set the client runs in emulator mode against that URL,
+//! which is how Azurite is used for offline testing. Azurite's well-known
+//! development account is used unless an account name/key is configured;
+//! SAS tokens are not supported there. object_store reads the emulator URL
+//! only from the environment, so `set_emulator_endpoint` sets it once at
+//! startup and all Azure remotes with an endpoint must share it.
+
+use object_store::azure::MicrosoftAzureBuilder;
+use object_store::{ClientOptions, ObjectStore};
+use crate::error::{Result, ToolError};
+use crate::config::{Config, StorageConfig, StorageProvider, TransferOptions};
+use std::sync::Arc;
+use tracing::info;
+use async_trait::async_trait;
+use super::faulty::FaultInjector;
+use super::{retry, wrap_store, StorageBackend, TransferContext};
+
+/// Environment variable object_store reads the emulator URL from
+const EMULATOR_URL_VAR: &str = "AZURITE_BLOB_STORAGE_URL";
+
+/// Point object_store's emulator mode at the endpoint of the configured
+/// Azure remotes. Changing the environment is only sound while no other
+/// thread runs, so this is called before the async runtime starts.
+pub fn set_emulator_endpoint(config: &Config) -> Result<()> {
+    let mut endpoints: Vec<&str> = std::iter::once(&config.default_storage)
+        .chain(config.remotes.values())
+        .filter(|storage| storage.provider == StorageProvider::Azure)
+        .filter_map(|storage| storage.endpoint.as_deref())
+        .map(|endpoint| endpoint.trim_end_matches('/'))
+        .collect();
+    endpoints.sort();
+    endpoints.dedup();
+
+    match endpoints.as_slice() {
+        [] => Ok(()),
+        [endpoint] => {
+            std::env::set_var(EMULATOR_URL_VAR, endpoint);
+            Ok(())
+        }
+        _ => Err(ToolError::Config(format!(
+            "Azure remotes use different endpoints ({}); only one Azure endpoint is supported",
+            endpoints.join(", ")
+        ))),
+    }
+}
+
+pub struct AzureStorage {
+    store: Arc<dyn ObjectStore>,
+    transfer: TransferOptions,
+    context: TransferContext,
+}
+
+impl AzureStorage {
+    pub fn new(config: &StorageConfig, transfer: &TransferOptions, context: &TransferContext) -> Result<Self> {
+        let container = config.bucket.clone()
+            .ok_or_else(|| ToolError::Config("Azure container not specified".into()))?;
+
+        info!("Building Azure storage with container: {}", container);
+
+        let mut builder = MicrosoftAzureBuilder::new()
+            .with_container_name(&container)
+            .with_client_options(ClientOptions::new()
+                .with_allow_invalid_certificates(config.allow_insecure));
+
+        if let Some(account) = &config.account_name {
+            builder = builder.with_account(account);
+        }
+
+        if let Some(key) = &config.account_key {
+            builder = builder.with_access_key(key);
+        } else if let Some(sas) = &config.sas_token {
+            builder = builder.try_with_option("azure_storage_sas_key", sas)?;
+        }
+
+        if let Some(endpoint) = &config.endpoint {
+            if config.sas_token.is_some() {
+                return Err(ToolError::Config("Azure SAS tokens cannot be used with a custom endpoint; configure account_name and account_key".into()));
+            }
+            if std::env::var(EMULATOR_URL_VAR).ok().as_deref() != Some(endpoint.trim_end_matches('/')) {
+                return Err(ToolError::Config(format!("Azure endpoint {} was not set up at startup", endpoint)));
+            }
+            builder = builder.with_use_emulator(true);
+        } else if config.account_name.is_none() {
+            return Err(ToolError::Config("Azure storage account name not specified".into()));
+        }
+
+        let store = builder
+            .with_retry(retry::store_retry_config(&retry::RetryPolicy::from_options(transfer)))
+            .build()?;
+
+        info!("Successfully initialized Azure storage");
+
+        Ok(Self {
+            store: wrap_store(Arc::new(store), FaultInjector::from_config(config), transfer),
+            transfer: transfer.clone(),
+            context: context.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for AzureStorage {
+    fn provider_name(&self) -> &'static str {
+        "Azure"
+    }
+
+    fn store(&self) -> &Arc<dyn ObjectStore> {
+        &self.store
+    }
+
+    fn transfer_options(&self) -> &TransferOptions {
+        &self.transfer
+    }
+
+    fn context(&self) -> &TransferContext {
+        &self.context
+    }
+}
@@ -0,0 +1,528 @@
+//! Bidirectional Sync Module
+//!
+//! Keeps a local directory and a remote prefix in step when files are
+//! edited on both sides. A baseline file records the size and modification
+//! time each file had on both sides after the last run; comparing the
+//! current listings with it tells which side changed a file since:
+//! - changed on one side: the change is copied to the other side
+//! - deleted on one side and unchanged on the other: deleted there too
+//! - new on one side: copied to the other side
+//! - changed on both sides, or new on both sides with different content:
+//!   a conflict, resolved by the chosen ConflictPolicy
+//!
+//! A file edited on one side and deleted on the other is restored from the
+//! edited side unless the policy is `fail`. Without a baseline (the first
+//! run) nothing is deleted, and files present on both sides are compared as
+//! `options.compare` selects.
+//!
+//! The baseline is JSON under `<data dir>/mytool/bisync`, named after a hash
+//! of both locations. It is rewritten after every run that gets past
+//! planning, with the new state of files whose changes were propagated and
+//! the previous state of those that failed, so failed changes are retried
+//! by the next run. Dry runs leave it alone.
+//!
+//! Deletes in each direction are checked against the `--max-delete`
+//! safeguards, with the other side as the source, so an empty or unmounted
+//! directory cannot wipe the remote.
+
+use super::compare;
+use super::filter::Filter;
+use super::plan::{Action, Plan, PlanEntry};
+use super::sync::{check_deletes, list_files_recursively, list_filtered, relative_key, run_transfers};
+use super::{StorageBackend, SyncOptions};
+use crate::error::{Result, ToolError};
+use super::journal::{self, Fingerprint};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, error, warn};
+
+/// How a bidirectional sync resolves files changed on both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the version with the later modification time
+    Newer,
+    /// Keep the local version under the file's name and the remote one as
+    /// `<name>.conflict-<unix time>`, on both sides
+    #[default]
+    KeepBoth,
+    /// Stop before changing anything and list the conflicts
+    Fail,
+}
+
+/// State of a file on both sides after the last run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct BaselineEntry {
+    local: Fingerprint,
+    remote: Fingerprint,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Baseline {
+    operation: String,
+    /// By path relative to both roots
+    files: BTreeMap<String, BaselineEntry>,
+}
+
+impl Baseline {
+    /// Read the baseline at `path`; None if there is none for `operation`
+    fn load(path: &Path, operation: &str) -> Result<Option<Self>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                error!("Error reading baseline {}: {}", path.display(), e);
+                return Err(ToolError::Io(e));
+            }
+        };
+        let baseline: Self = serde_json::from_slice(&data)
+            .map_err(|e| ToolError::Config(format!("Corrupt baseline {}: {}; remove it to start over", path.display(), e)))?;
+        if baseline.operation != operation {
+            warn!("Ignoring baseline {} written for another sync", path.display());
+            return Ok(None);
+        }
+        Ok(Some(baseline))
+    }
+
+    /// Replace the baseline at `path`; the file is written next to it and
+    /// renamed into place, so an interrupted write keeps the old one
+    fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| ToolError::Config(format!("Cannot create baseline directory {}: {}", dir.display(), e)))?;
+        }
+        let data = serde_json::to_vec(self)
+            .map_err(|e| ToolError::InvalidOperation(format!("Cannot encode baseline: {}", e)))?;
+        let staging = path.with_extension("json.tmp");
+        std::fs::write(&staging, data)
+            .and_then(|()| std::fs::rename(&staging, path))
+            .map_err(|e| {
+                error!("Error writing baseline {}: {}", path.display(), e);
+                ToolError::Io(e)
+            })
+    }
+}
+
+/// Where the baseline of syncing `operation` is kept by default
+pub fn default_baseline(operation: &str) -> Option<PathBuf> {
+    dirs::data_local_dir().map(|mut dir| {
+        dir.push("mytool");
+        dir.push("bisync");
+        dir.push(format!("{:016x}.json", journal::fnv1a(operation.as_bytes())));
+        dir
+    })
+}
+
+/// What a run does with one file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Upload,
+    Download,
+    DeleteRemote,
+    DeleteLocal,
+    /// Save the remote version as a conflict copy on both sides, then
+    /// upload the local one
+    KeepBoth,
+}
+
+type Listing = HashMap<String, (u64, SystemTime)>;
+
+/// What the changes to a file since the last run call for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Decision {
+    /// Unchanged, or deleted on both sides
+    Leave,
+    /// Carry out the step, for the reason given
+    Step(Step, &'static str),
+    /// Changed on both sides, which ConflictPolicy::Fail refuses
+    Conflict,
+    /// Changed on both sides; the version modified last wins
+    Newer,
+}
+
+/// Decide what to do with a file from whether the baseline `known` it,
+/// which sides `changed` it since and on which it `exists` now (local,
+/// remote)
+fn decide(known: bool, changed: (bool, bool), exists: (bool, bool), policy: ConflictPolicy) -> Decision {
+    let (local_change, remote_change) = match known {
+        true => ("changed locally", "changed remotely"),
+        false => ("new locally", "new remotely"),
+    };
+    match (changed, exists) {
+        ((false, false), _) => Decision::Leave,
+        ((true, false), (true, _)) => Decision::Step(Step::Upload, local_change),
+        ((true, false), (false, _)) => Decision::Step(Step::DeleteRemote, "deleted locally"),
+        ((false, true), (_, true)) => Decision::Step(Step::Download, remote_change),
+        ((false, true), (_, false)) => Decision::Step(Step::DeleteLocal, "deleted remotely"),
+        ((true, true), (false, false)) => Decision::Leave,
+        ((true, true), _) if policy == ConflictPolicy::Fail => Decision::Conflict,
+        ((true, true), (true, false)) => Decision::Step(Step::Upload, "changed locally, deleted remotely; restored"),
+        ((true, true), (false, true)) => Decision::Step(Step::Download, "changed remotely, deleted locally; restored"),
+        ((true, true), (true, true)) => match policy {
+            ConflictPolicy::KeepBoth => Decision::Step(Step::KeepBoth, "conflict: changed on both sides; remote version kept as a conflict copy"),
+            _ => Decision::Newer,
+        },
+    }
+}
+
+fn fingerprint(state: Option<&(u64, SystemTime)>) -> Option<Fingerprint> {
+    state.map(|(size, modified)| Fingerprint::new(*size, *modified))
+}
+
+/// Baseline entry for a file in the given states, if it exists on both sides
+fn entry(local: Option<&(u64, SystemTime)>, remote: Option<&(u64, SystemTime)>) -> Option<BaselineEntry> {
+    Some(BaselineEntry { local: fingerprint(local)?, remote: fingerprint(remote)? })
+}
+
+fn remote_key(prefix: &str, rel_path: &str) -> String {
+    if prefix.is_empty() {
+        rel_path.to_string()
+    } else {
+        format!("{}/{}", prefix.trim_matches('/'), rel_path)
+    }
+}
+
+/// Files under `local_dir` by relative path, with their size and mtime
+async fn list_local(local_dir: &Path, filter: Option<&Filter>, plan: Option<&Plan>) -> Result<Listing> {
+    let mut files = HashMap::new();
+    if !local_dir.exists() {
+        return Ok(files);
+    }
+    for (local_path, rel_path) in list_files_recursively(local_dir, filter, plan).await? {
+        let metadata = tokio::fs::metadata(&local_path).await
+            .map_err(|e| {
+                error!("Error getting file metadata: {}", e);
+                ToolError::Io(e)
+            })?;
+        let mtime = metadata.modified()
+            .map_err(|e| {
+                error!("Error getting file mtime: {}", e);
+                ToolError::Io(e)
+            })?;
+        files.insert(rel_path.to_string_lossy().to_string(), (metadata.len(), mtime));
+    }
+    Ok(files)
+}
+
+/// Objects under `prefix` by path relative to it
+async fn list_remote<B: StorageBackend + ?Sized>(backend: &B, prefix: &str, plan: Option<&Plan>) -> Result<Listing> {
+    Ok(list_filtered(backend, prefix, backend.filter(), plan).await?
+        .into_iter()
+        .map(|(key, state)| (relative_key(&key, prefix).to_string(), state))
+        .collect())
+}
+
+pub(super) async fn sync_bidirectional<B: StorageBackend + ?Sized>(
+    backend: &B,
+    local_dir: &Path,
+    remote_prefix: &str,
+    baseline_path: &Path,
+    policy: ConflictPolicy,
+    options: &SyncOptions,
+) -> Result<()> {
+    info!("Syncing {} and remote {} in both directions", local_dir.display(), remote_prefix);
+    let plan = backend.plan();
+    let operation = format!("bisync {} {}", std::path::absolute(local_dir)?.display(), remote_prefix.trim_matches('/'));
+    let baseline = Baseline::load(baseline_path, &operation)?;
+    if baseline.is_none() {
+        info!("No baseline at {}; nothing is deleted on this first run", baseline_path.display());
+    }
+    let previous = baseline.map(|baseline| baseline.files).unwrap_or_default();
+
+    let local = list_local(local_dir, backend.filter(), plan).await?;
+    let remote = list_remote(backend, remote_prefix, plan).await?;
+    let local_path = |rel_path: &str| local_dir.join(rel_path);
+
+    // Files new on both sides are the same file if they compare equal
+    let unseen: Vec<_> = local.iter()
+        .filter(|(rel_path, _)| !previous.contains_key(*rel_path))
+        .filter_map(|(rel_path, (size, time))| match remote.get(rel_path) {
+            Some((remote_size, remote_time)) if remote_size == size => Some((rel_path.clone(), *time, *remote_time)),
+            _ => None,
+        })
+        .collect();
+    let identical: HashSet<String> = stream::iter(unseen)
+        .map(|(rel_path, local_time, remote_time)| async move {
+            let key = remote_key(remote_prefix, &rel_path);
+            let current = compare::upload_is_current(backend, options.compare, &local_path(&rel_path), local_time, &key, remote_time).await?;
+            Ok::<_, ToolError>((rel_path, current))
+        })
+        .buffer_unordered(backend.transfer_options().concurrent_uploads.max(1))
+        .try_filter_map(|(rel_path, current)| async move { Ok(current.then_some(rel_path)) })
+        .try_collect()
+        .await?;
+
+    let mut paths: Vec<&String> = local.keys().chain(remote.keys()).chain(previous.keys()).collect();
+    paths.sort();
+    paths.dedup();
+
+    let mut steps = Vec::new();
+    let mut conflicts = Vec::new();
+    let mut planned = Vec::new();
+    for rel_path in paths {
+        let (now_local, now_remote) = (local.get(rel_path), remote.get(rel_path));
+        let key = remote_key(remote_prefix, rel_path);
+        let local_label = local_path(rel_path).display().to_string();
+        let (local_changed, remote_changed) = match previous.get(rel_path) {
+            Some(entry) => (fingerprint(now_local) != Some(entry.local), fingerprint(now_remote) != Some(entry.remote)),
+            None if identical.contains(rel_path) => (false, false),
+            None => (now_local.is_some(), now_remote.is_some()),
+        };
+
+        let decision = decide(
+            previous.contains_key(rel_path),
+            (local_changed, remote_changed),
+            (now_local.is_some(), now_remote.is_some()),
+            policy,
+        );
+        let size = |state: Option<&(u64, SystemTime)>| state.map(|(size, _)| *size);
+        let step = match decision {
+            Decision::Leave => {
+                if let (Some((size, _)), Some(_)) = (now_local, now_remote) {
+                    planned.push(PlanEntry::new(Action::Skip, key, Some(*size), "unchanged since the last sync"));
+                }
+                continue;
+            }
+            Decision::Conflict => {
+                planned.push(PlanEntry::new(Action::Skip, &key, None, "conflict: changed on both sides"));
+                conflicts.push(rel_path.clone());
+                continue;
+            }
+            Decision::Newer => {
+                let (Some((local_size, local_time)), Some((remote_size, remote_time))) = (now_local, now_remote) else {
+                    unreachable!("decide only picks the newer version of files on both sides");
+                };
+                // The remote side's own mtime, if it was stored
+                let remote_time = backend.file_metadata(&key).await?
+                    .and_then(|attributes| attributes.mtime)
+                    .unwrap_or(*remote_time);
+                if *local_time >= remote_time {
+                    planned.push(PlanEntry::new(Action::Update, &key, Some(*local_size), "conflict: changed on both sides; local version is newer"));
+                    Step::Upload
+                } else {
+                    planned.push(PlanEntry::new(Action::Update, &local_label, Some(*remote_size), "conflict: changed on both sides; remote version is newer"));
+                    Step::Download
+                }
+            }
+            Decision::Step(step, reason) => {
+                planned.push(match step {
+                    Step::Upload => {
+                        let action = if now_remote.is_some() { Action::Update } else { Action::Upload };
+                        PlanEntry::new(action, &key, size(now_local), reason)
+                    }
+                    Step::Download => {
+                        let action = if now_local.is_some() { Action::Update } else { Action::Download };
+                        PlanEntry::new(action, &local_label, size(now_remote), reason)
+                    }
+                    Step::DeleteRemote => PlanEntry::new(Action::Delete, &key, size(now_remote), reason),
+                    Step::DeleteLocal => PlanEntry::new(Action::Delete, &local_label, size(now_local), reason),
+                    Step::KeepBoth => PlanEntry::new(Action::Update, &key, size(now_local), reason),
+                });
+                step
+            }
+        };
+        steps.push((rel_path.clone(), step));
+    }
+
+    if !conflicts.is_empty() {
+        let message = format!("{} files changed on both sides: {}", conflicts.len(), conflicts.join(", "));
+        match plan {
+            Some(_) => warn!("{}", message),
+            None => {
+                error!("{}", message);
+                return Err(ToolError::InvalidOperation(format!("{}; choose another --conflict policy or resolve them by hand", message)));
+            }
+        }
+    }
+
+    let remote_deletes = steps.iter().filter(|(_, step)| *step == Step::DeleteRemote).count();
+    let local_deletes = steps.iter().filter(|(_, step)| *step == Step::DeleteLocal).count();
+    check_deletes(plan, options, &local_dir.display().to_string(), local.len(), remote.len(), remote_deletes)?;
+    check_deletes(plan, options, remote_prefix, remote.len(), local.len(), local_deletes)?;
+
+    if let Some(plan) = plan {
+        plan.extend(planned);
+        return Ok(());
+    }
+
+    let conflict_suffix = format!(".conflict-{}", SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    let done = Mutex::new(HashSet::new());
+    let items = steps.iter()
+        .filter(|(_, step)| *step != Step::DeleteRemote)
+        .map(|(rel_path, step)| (rel_path.clone(), (rel_path, *step)))
+        .collect();
+    let result = run_transfers(backend.transfer_options().concurrent_uploads, items, |(rel_path, step)| {
+        let (done, conflict_suffix) = (&done, &conflict_suffix);
+        async move {
+            let key = remote_key(remote_prefix, rel_path);
+            let path = local_path(rel_path);
+            match step {
+                Step::Upload => backend.upload_file(&path, &key).await?,
+                Step::Download => backend.download_file(&key, &path).await?,
+                Step::DeleteRemote => unreachable!("remote deletes are batched"),
+                Step::DeleteLocal => {
+                    info!("Deleting {}", path.display());
+                    tokio::fs::remove_file(&path).await
+                        .map_err(|e| {
+                            error!("Error deleting file: {}", e);
+                            ToolError::Io(e)
+                        })?;
+                }
+                Step::KeepBoth => {
+                    let copy = format!("{}{}", rel_path, conflict_suffix);
+                    info!("Keeping the remote version of {} as {}", rel_path, copy);
+                    backend.copy_object(&key, &remote_key(remote_prefix, &copy)).await?;
+                    backend.download_file(&remote_key(remote_prefix, &copy), &local_path(&copy)).await?;
+                    backend.upload_file(&path, &key).await?;
+                }
+            }
+            done.lock().unwrap().insert(rel_path.clone());
+            Ok(())
+        }
+    }).await;
+
+    // Remote deletes go out in batches
+    let mut done = done.into_inner().unwrap();
+    let deletes: Vec<(&String, String)> = steps.iter()
+        .filter(|(_, step)| *step == Step::DeleteRemote)
+        .map(|(rel_path, _)| (rel_path, remote_key(remote_prefix, rel_path)))
+        .collect();
+    let keys: Vec<String> = deletes.iter().map(|(_, key)| key.clone()).collect();
+    let delete_result = if keys.is_empty() { Ok(()) } else { backend.delete_objects(&keys).await };
+    let failed_deletes: HashSet<&str> = match &delete_result {
+        Ok(()) => HashSet::new(),
+        Err(ToolError::Transfers { failures, .. }) => failures.iter().map(|(key, _)| key.as_str()).collect(),
+        Err(_) => keys.iter().map(String::as_str).collect(),
+    };
+    for (rel_path, key) in &deletes {
+        if !failed_deletes.contains(key.as_str()) {
+            done.insert((*rel_path).clone());
+        }
+    }
+
+    // The side a change came from keeps the state it was listed with; the
+    // side it went to is listed again. Files whose step failed keep their
+    // previous state, so the next run tries again.
+    let local_after = list_local(local_dir, backend.filter(), None).await?;
+    let remote_after = list_remote(backend, remote_prefix, None).await?;
+    let mut files = previous;
+    for (rel_path, step) in &steps {
+        if !done.contains(rel_path) {
+            continue;
+        }
+        let state = match step {
+            Step::Upload => entry(local.get(rel_path), remote_after.get(rel_path)),
+            Step::Download => entry(local_after.get(rel_path), remote.get(rel_path)),
+            Step::DeleteRemote | Step::DeleteLocal => None,
+            Step::KeepBoth => {
+                let copy = format!("{}{}", rel_path, conflict_suffix);
+                if let Some(state) = entry(local_after.get(&copy), remote_after.get(&copy)) {
+                    files.insert(copy, state);
+                }
+                entry(local.get(rel_path), remote_after.get(rel_path))
+            }
+        };
+        match state {
+            Some(state) => files.insert(rel_path.clone(), state),
+            None => files.remove(rel_path),
+        };
+    }
+    // Unchanged files and files that were new on both sides with the same
+    // content are recorded as listed; files deleted on both sides are dropped
+    let stepped: HashSet<&String> = steps.iter().map(|(rel_path, _)| rel_path).collect();
+    files.retain(|rel_path, _| stepped.contains(rel_path) || (local.contains_key(rel_path) && remote.contains_key(rel_path)));
+    for rel_path in local.keys().filter(|rel_path| !stepped.contains(rel_path)) {
+        if let Some(state) = entry(local.get(rel_path), remote.get(rel_path)) {
+            files.insert(rel_path.clone(), state);
+        }
+    }
+
+    Baseline { operation, files }.save(baseline_path)?;
+    result?;
+    delete_result?;
+
+    info!("Successfully synced {} and {} in both directions", local_dir.display(), remote_prefix);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: (bool, bool) = (true, true);
+    const LOCAL: (bool, bool) = (true, false);
+    const REMOTE: (bool, bool) = (false, true);
+    const NEITHER: (bool, bool) = (false, false);
+
+    #[test]
+    fn unchanged_files_are_left_alone() {
+        assert_eq!(decide(true, NEITHER, BOTH, ConflictPolicy::Fail), Decision::Leave);
+        assert_eq!(decide(true, BOTH, NEITHER, ConflictPolicy::Fail), Decision::Leave);
+    }
+
+    #[test]
+    fn changes_on_one_side_are_copied_to_the_other() {
+        assert_eq!(decide(true, LOCAL, BOTH, ConflictPolicy::KeepBoth), Decision::Step(Step::Upload, "changed locally"));
+        assert_eq!(decide(true, REMOTE, BOTH, ConflictPolicy::KeepBoth), Decision::Step(Step::Download, "changed remotely"));
+        assert_eq!(decide(false, LOCAL, LOCAL, ConflictPolicy::KeepBoth), Decision::Step(Step::Upload, "new locally"));
+        assert_eq!(decide(false, REMOTE, REMOTE, ConflictPolicy::KeepBoth), Decision::Step(Step::Download, "new remotely"));
+    }
+
+    #[test]
+    fn deletes_on_one_side_are_copied_to_the_other() {
+        assert_eq!(decide(true, LOCAL, REMOTE, ConflictPolicy::KeepBoth), Decision::Step(Step::DeleteRemote, "deleted locally"));
+        assert_eq!(decide(true, REMOTE, LOCAL, ConflictPolicy::KeepBoth), Decision::Step(Step::DeleteLocal, "deleted remotely"));
+    }
+
+    #[test]
+    fn a_file_changed_on_one_side_and_deleted_on_the_other_is_restored() {
+        assert_eq!(
+            decide(true, BOTH, LOCAL, ConflictPolicy::Newer),
+            Decision::Step(Step::Upload, "changed locally, deleted remotely; restored"),
+        );
+        assert_eq!(
+            decide(true, BOTH, REMOTE, ConflictPolicy::KeepBoth),
+            Decision::Step(Step::Download, "changed remotely, deleted locally; restored"),
+        );
+        assert_eq!(decide(true, BOTH, LOCAL, ConflictPolicy::Fail), Decision::Conflict);
+    }
+
+    #[test]
+    fn conflicts_follow_the_policy() {
+        assert!(matches!(decide(true, BOTH, BOTH, ConflictPolicy::KeepBoth), Decision::Step(Step::KeepBoth, _)));
+        assert_eq!(decide(true, BOTH, BOTH, ConflictPolicy::Newer), Decision::Newer);
+        assert_eq!(decide(true, BOTH, BOTH, ConflictPolicy::Fail), Decision::Conflict);
+        // New on both sides with different content
+        assert_eq!(decide(false, BOTH, BOTH, ConflictPolicy::Fail), Decision::Conflict);
+    }
+
+    #[tokio::test]
+    async fn propagates_new_files_then_deletes_against_the_baseline() {
+        use crate::config::{Config, StorageConfig};
+        use crate::storage::memory::MemoryStorage;
+        use crate::storage::TransferContext;
+
+        let local = tempfile::tempdir().unwrap();
+        let state = tempfile::tempdir().unwrap();
+        let baseline = state.path().join("baseline.json");
+        let remote = MemoryStorage::new(&StorageConfig::default(), &Config::default().transfer_options, &TransferContext::default());
+        let options = SyncOptions::default();
+
+        std::fs::write(local.path().join("local.txt"), b"written locally").unwrap();
+        let other = state.path().join("remote.txt");
+        std::fs::write(&other, b"written remotely").unwrap();
+        remote.upload_file(&other, "runs/remote.txt").await.unwrap();
+
+        remote.sync_bidirectional(local.path(), "runs", &baseline, ConflictPolicy::Fail, &options).await.unwrap();
+        assert_eq!(std::fs::read(local.path().join("remote.txt")).unwrap(), b"written remotely");
+        assert!(remote.list_files_with_metadata("runs").await.unwrap().contains_key("runs/local.txt"));
+
+        std::fs::remove_file(local.path().join("local.txt")).unwrap();
+        remote.sync_bidirectional(local.path(), "runs", &baseline, ConflictPolicy::Fail, &options).await.unwrap();
+        let listed = remote.list_files_with_metadata("runs").await.unwrap();
+        assert_eq!(listed.keys().collect::<Vec<_>>(), ["runs/remote.txt"]);
+    }
+}
@@ -0,0 +1,360 @@
+//! Sync Comparison Module
+//!
+//! Decides whether a file that exists on both sides of a sync has to be
+//! transferred again. Files of different sizes always are; for files of the
+//! same size the strategy selected with `sync --compare` decides:
+//! - size: the same size means unchanged
+//! - mtime (default): modification times, preferring the source file's
+//!   mtime stored with the object (see the metadata module)
+//! - checksum: SHA-256 of the content. Remote hashes come from the stored
+//!   `mytool-sha256` attribute, else from the object's ETag, else from
+//!   reading the object.
+//! - etag: S3 ETags. Local files are hashed the way S3 computes ETags: the
+//!   MD5 of the content, or for multipart uploads the MD5 of the part MD5s
+//!   followed by `-<parts>`. Objects without an ETag are compared by
+//!   checksum instead.
+//!
+//! Multipart ETags depend on the part size, which S3 does not report; it is
+//! guessed from the part count, trying this tool's part size and common
+//! client defaults. ETags of objects encrypted with SSE-KMS are not MD5s and
+//! never match, so those files are transferred again.
+
+use super::metadata::HASH_BUFFER_SIZE;
+use super::StorageBackend;
+use crate::error::{Result, ToolError};
+use futures::StreamExt;
+use md5::{Digest, Md5};
+use object_store::path::Path as ObjectPath;
+use sha2::Sha256;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, error};
+
+/// S3's minimum part size and part count limit, which bound the part sizes
+/// a multipart ETag can have been computed with
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_PARTS: u64 = 10_000;
+const MIB: u64 = 1024 * 1024;
+
+/// How a sync compares files present on both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Compare {
+    /// Transfer only files whose size differs
+    Size,
+    /// Also transfer files whose modification time differs
+    #[default]
+    Mtime,
+    /// Compare SHA-256 hashes of the content
+    Checksum,
+    /// Compare S3 ETags, computing them for local files
+    Etag,
+}
+
+impl Compare {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compare::Size => "size",
+            Compare::Mtime => "mtime",
+            Compare::Checksum => "checksum",
+            Compare::Etag => "etag",
+        }
+    }
+}
+
+/// Whether the object at `remote_path` is current for the local file it
+/// would be uploaded from
+pub(super) async fn upload_is_current<B: StorageBackend + ?Sized>(
+    backend: &B,
+    compare: Compare,
+    local_path: &Path,
+    local_time: SystemTime,
+    remote_path: &str,
+    remote_time: SystemTime,
+) -> Result<bool> {
+    match compare {
+        Compare::Size => Ok(true),
+        // Without a stored mtime, an object written after the file was last
+        // modified is current
+        Compare::Mtime => Ok(match stored_mtime(backend, remote_path).await? {
+            Some(source_time) => source_time == local_time,
+            None => remote_time >= local_time,
+        }),
+        Compare::Checksum | Compare::Etag => local_matches_remote(backend, compare, local_path, remote_path).await,
+    }
+}
+
+/// Whether the local file at `local_path` is current for the object it
+/// would be downloaded from
+pub(super) async fn download_is_current<B: StorageBackend + ?Sized>(
+    backend: &B,
+    compare: Compare,
+    remote_path: &str,
+    remote_time: SystemTime,
+    local_path: &Path,
+    local_time: SystemTime,
+) -> Result<bool> {
+    match compare {
+        Compare::Size => Ok(true),
+        // Without a stored mtime, downloads give the file the object's own
+        // modification time. It is compared to the second, as providers
+        // report it at different precisions.
+        Compare::Mtime => Ok(match stored_mtime(backend, remote_path).await? {
+            Some(source_time) => source_time == local_time,
+            None => whole_seconds(local_time) == whole_seconds(remote_time),
+        }),
+        Compare::Checksum | Compare::Etag => local_matches_remote(backend, compare, local_path, remote_path).await,
+    }
+}
+
+/// Whether `dest_path` of `dest` is current for `src_path` of `source`
+pub(super) async fn copy_is_current<D: StorageBackend + ?Sized>(
+    source: &dyn StorageBackend,
+    src_path: &str,
+    src_time: SystemTime,
+    dest: &D,
+    dest_path: &str,
+    dest_time: SystemTime,
+    compare: Compare,
+) -> Result<bool> {
+    match compare {
+        Compare::Size => Ok(true),
+        // Both objects carry the mtime of the file they came from if this
+        // tool uploaded them; otherwise a destination written after the
+        // source is current
+        Compare::Mtime => {
+            let (src_mtime, dest_mtime) = futures::try_join!(
+                stored_mtime(source, src_path),
+                stored_mtime(dest, dest_path),
+            )?;
+            Ok(match (src_mtime, dest_mtime) {
+                (Some(src_mtime), Some(dest_mtime)) => dest_mtime == src_mtime,
+                _ => dest_time >= src_time,
+            })
+        }
+        Compare::Checksum | Compare::Etag => {
+            let (src_attributes, dest_attributes) = futures::try_join!(
+                source.file_metadata(src_path),
+                dest.file_metadata(dest_path),
+            )?;
+            let src_attributes = src_attributes.unwrap_or_default();
+            let dest_attributes = dest_attributes.unwrap_or_default();
+
+            if compare == Compare::Checksum {
+                if let (Some(src_hash), Some(dest_hash)) = (&src_attributes.sha256, &dest_attributes.sha256) {
+                    return Ok(src_hash == dest_hash);
+                }
+            }
+            if let (Some(src_etag), Some(dest_etag)) = (&src_attributes.etag, &dest_attributes.etag) {
+                // Multipart ETags of the same content differ if the part
+                // sizes did, so only equal or single-part ETags are decisive
+                if src_etag == dest_etag || compare == Compare::Etag || !(is_multipart(src_etag) || is_multipart(dest_etag)) {
+                    return Ok(src_etag == dest_etag);
+                }
+            }
+
+            let (src_hash, dest_hash) = futures::try_join!(
+                source.content_sha256(src_path),
+                dest.content_sha256(dest_path),
+            )?;
+            Ok(src_hash == dest_hash)
+        }
+    }
+}
+
+async fn stored_mtime<B: StorageBackend + ?Sized>(backend: &B, remote_path: &str) -> Result<Option<SystemTime>> {
+    Ok(backend.file_metadata(remote_path).await?.and_then(|attributes| attributes.mtime))
+}
+
+fn whole_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Compare a local file's content with an object's, as cheaply as the
+/// object's attributes allow
+async fn local_matches_remote<B: StorageBackend + ?Sized>(backend: &B, compare: Compare, local_path: &Path, remote_path: &str) -> Result<bool> {
+    let attributes = backend.file_metadata(remote_path).await?.unwrap_or_default();
+
+    if compare == Compare::Checksum {
+        if let Some(remote_hash) = &attributes.sha256 {
+            return Ok(*remote_hash == super::metadata::sha256_file(local_path).await?);
+        }
+    }
+    if let Some(etag) = &attributes.etag {
+        let chunk_size = backend.transfer_options().chunk_size as u64;
+        if let Some(matches) = etag_matches(local_path, etag, chunk_size).await? {
+            return Ok(matches);
+        }
+        debug!("Cannot tell from ETag {} whether {} changed", etag, remote_path);
+    }
+
+    let (local_hash, remote_hash) = futures::try_join!(
+        super::metadata::sha256_file(local_path),
+        backend.content_sha256(remote_path),
+    )?;
+    Ok(local_hash == remote_hash)
+}
+
+fn is_multipart(etag: &str) -> bool {
+    etag.contains('-')
+}
+
+/// Whether `etag` is the S3 ETag of the local file, or None if that cannot
+/// be told because no plausible part size yields its part count
+async fn etag_matches(path: &Path, etag: &str, chunk_size: u64) -> Result<Option<bool>> {
+    let Some((_, parts)) = etag.split_once('-') else {
+        return Ok(Some(s3_etag(path, None).await? == etag));
+    };
+    let Ok(parts) = parts.parse::<u64>() else {
+        return Ok(None);
+    };
+
+    let size = tokio::fs::metadata(path).await
+        .map_err(|e| {
+            error!("Error getting file metadata of {}: {}", path.display(), e);
+            ToolError::Io(e)
+        })?
+        .len();
+    let candidates = part_size_candidates(size, parts, chunk_size);
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    for part_size in candidates {
+        if s3_etag(path, Some(part_size)).await? == etag {
+            return Ok(Some(true));
+        }
+    }
+    Ok(Some(false))
+}
+
+/// Part sizes that split `size` bytes into exactly `parts` parts: the one
+/// this tool uploads with, common client defaults, and the smallest whole
+/// MiB that fits
+fn part_size_candidates(size: u64, parts: u64, chunk_size: u64) -> Vec<u64> {
+    let ours = chunk_size.max(MIN_PART_SIZE).max(size.div_ceil(MAX_PARTS));
+    let fitted = size.div_ceil(parts).div_ceil(MIB) * MIB;
+    let mut candidates = Vec::new();
+    for part_size in [ours, chunk_size, 8 * MIB, 16 * MIB, MIN_PART_SIZE, fitted] {
+        if part_size > 0 && size.div_ceil(part_size) == parts && !candidates.contains(&part_size) {
+            candidates.push(part_size);
+        }
+    }
+    candidates
+}
+
+/// The ETag S3 gives a local file uploaded in one piece, or in parts of
+/// `part_size` bytes
+async fn s3_etag(path: &Path, part_size: Option<u64>) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await
+        .map_err(|e| {
+            error!("Error opening file {}: {}", path.display(), e);
+            ToolError::Io(e)
+        })?;
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    let mut part = Md5::new();
+    let mut part_length = 0u64;
+    let mut digests = Vec::new();
+
+    loop {
+        let limit = match part_size {
+            Some(part_size) => (part_size - part_length).min(buffer.len() as u64) as usize,
+            None => buffer.len(),
+        };
+        let read = file.read(&mut buffer[..limit]).await
+            .map_err(|e| {
+                error!("Error reading file {}: {}", path.display(), e);
+                ToolError::Io(e)
+            })?;
+        if read == 0 {
+            break;
+        }
+        part.update(&buffer[..read]);
+        part_length += read as u64;
+        if Some(part_length) == part_size {
+            digests.extend_from_slice(&part.finalize_reset());
+            part_length = 0;
+        }
+    }
+
+    match part_size {
+        None => Ok(hex::encode(part.finalize())),
+        Some(_) => {
+            if part_length > 0 {
+                digests.extend_from_slice(&part.finalize());
+            }
+            Ok(format!("{}-{}", hex::encode(Md5::digest(&digests)), digests.len() / 16))
+        }
+    }
+}
+
+/// Hex SHA-256 of an object's content, read through the backend's store
+pub(super) async fn sha256_object<B: StorageBackend + ?Sized>(backend: &B, path: &str) -> Result<String> {
+    let location = ObjectPath::from(path);
+    let mut stream = backend.store().get(&location).await
+        .map_err(|e| {
+            error!("Error reading {} from {}: {}", path, backend.provider_name(), e);
+            ToolError::Storage(e)
+        })?
+        .into_stream();
+
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk
+            .map_err(|e| {
+                error!("Error reading {} from {}: {}", path, backend.provider_name(), e);
+                ToolError::Storage(e)
+            })?;
+        hasher.update(&chunk);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file of `len` bytes of a repeating pattern; the expected ETags below
+    /// were computed independently
+    fn pattern(len: usize) -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let content: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(file.path(), content).unwrap();
+        file
+    }
+
+    #[test]
+    fn part_size_candidates_keep_sizes_that_yield_the_part_count() {
+        assert_eq!(part_size_candidates(20 * MIB, 3, 8 * MIB), vec![8 * MIB, 7 * MIB]);
+        assert_eq!(part_size_candidates(12 * MIB, 3, 0), vec![5 * MIB, 4 * MIB]);
+    }
+
+    #[test]
+    fn part_size_candidates_are_empty_when_no_size_fits() {
+        assert!(part_size_candidates(MIB, 5, 8 * MIB).is_empty());
+    }
+
+    #[tokio::test]
+    async fn s3_etag_of_single_part_upload_is_content_md5() {
+        let file = pattern(12 * MIB as usize);
+        assert_eq!(s3_etag(file.path(), None).await.unwrap(), "0678042110afa53b12c87ef1e06d2654");
+    }
+
+    #[tokio::test]
+    async fn s3_etag_of_multipart_upload_hashes_part_md5s() {
+        let file = pattern(12 * MIB as usize);
+        assert_eq!(s3_etag(file.path(), Some(5 * MIB)).await.unwrap(), "7df28755d1a6cc911533a3b50170cf7d-3");
+
+        // A size that is a multiple of the part size has no empty last part
+        let file = pattern(10 * MIB as usize);
+        assert_eq!(s3_etag(file.path(), Some(5 * MIB)).await.unwrap(), "1e4f501cf04fe65dfa57940273a0cecf-2");
+    }
+
+    #[tokio::test]
+    async fn etag_matches_guesses_the_part_size() {
+        let file = pattern(12 * MIB as usize);
+        // Uploaded by another client in 5 MiB parts while this tool uses 8 MiB
+        assert_eq!(etag_matches(file.path(), "7df28755d1a6cc911533a3b50170cf7d-3", 8 * MIB).await.unwrap(), Some(true));
+        assert_eq!(etag_matches(file.path(), "00000000000000000000000000000000-3", 8 * MIB).await.unwrap(), Some(false));
+        assert_eq!(etag_matches(file.path(), "00000000000000000000000000000000-7000", 8 * MIB).await.unwrap(), None);
+    }
+}
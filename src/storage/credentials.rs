@@ -0,0 +1,285 @@
+//! AWS Credential Resolution Module
+//!
+//! Resolves S3 credentials once per remote and shares the result between the
+//! AWS SDK client and the object_store client, so both always authenticate
+//! as the same identity.
+//!
+//! Precedence:
+//! 1. Explicit profile (`--profile` flag or `profile` in the remote config):
+//!    only that profile is tried
+//! 2. `access_key_id` / `secret_access_key` / `session_token` in the config
+//! 3. Environment: AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY, AWS_SESSION_TOKEN
+//! 4. Web identity: AWS_WEB_IDENTITY_TOKEN_FILE + AWS_ROLE_ARN
+//! 5. Shared profile files (~/.aws/credentials, ~/.aws/config) for
+//!    AWS_PROFILE or "default"
+//! 6. EC2 instance metadata (IMDS)
+//!
+//! If no source yields credentials the error lists every source that was
+//! tried and why it failed.
+//!
+//! Temporary credentials object_store cannot fetch by itself, such as those
+//! of profiles that assume a role, are fetched again through the SDK provider
+//! shortly before they expire, so transfers can outlast them.
+
+use aws_config::environment::EnvironmentVariableCredentialsProvider;
+use aws_config::imds::credentials::ImdsCredentialsProvider;
+use aws_config::profile::ProfileFileCredentialsProvider;
+use aws_config::provider_config::ProviderConfig;
+use aws_config::web_identity_token::WebIdentityTokenCredentialsProvider;
+use aws_credential_types::provider::{ProvideCredentials, SharedCredentialsProvider};
+use aws_credential_types::Credentials;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path as ObjectPath;
+use object_store::{GetResult, ListResult, MultipartId, ObjectMeta, ObjectStore};
+use crate::config::StorageConfig;
+use crate::error::{Result, ToolError};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncWrite;
+use tokio::sync::Mutex;
+use tracing::{info, debug};
+
+/// How long before they expire temporary credentials are replaced
+const REFRESH_MARGIN: Duration = Duration::from_secs(10 * 60);
+
+/// Where a set of credentials came from
+#[derive(Debug, Clone, PartialEq)]
+pub enum CredentialSource {
+    Profile(String),
+    Config,
+    Environment,
+    WebIdentity,
+    InstanceMetadata,
+}
+
+impl std::fmt::Display for CredentialSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialSource::Profile(name) => write!(f, "profile '{}'", name),
+            CredentialSource::Config => write!(f, "config file"),
+            CredentialSource::Environment => write!(f, "environment variables"),
+            CredentialSource::WebIdentity => write!(f, "web identity token"),
+            CredentialSource::InstanceMetadata => write!(f, "instance metadata"),
+        }
+    }
+}
+
+/// The winning credential source, ready to configure both S3 clients
+pub struct ResolvedCredentials {
+    pub source: CredentialSource,
+    /// Provider for the SDK client; refreshes temporary credentials itself
+    pub provider: SharedCredentialsProvider,
+    /// Credentials as resolved, for the object_store client; sources whose
+    /// credentials expire are asked for new ones through `provider`
+    pub credentials: Credentials,
+}
+
+impl ResolvedCredentials {
+    /// Build the object_store client with the same identity as the SDK client.
+    ///
+    /// Web identity and instance metadata credentials expire, so object_store
+    /// is left to run its own provider for those sources (it reads the same
+    /// environment and metadata endpoint) and refresh them as needed. Other
+    /// credentials that expire, such as those of profiles that assume a role,
+    /// go to a RefreshingStore; the rest are passed as they are.
+    pub fn build_store(&self, builder: AmazonS3Builder) -> Result<Arc<dyn ObjectStore>> {
+        match self.source {
+            CredentialSource::WebIdentity | CredentialSource::InstanceMetadata => Ok(Arc::new(builder.build()?)),
+            _ => match self.credentials.expiry() {
+                Some(expiry) => {
+                    info!("AWS credentials from {} expire; they are refreshed before they do", self.source);
+                    let store = with_credentials(builder.clone(), &self.credentials).build()?;
+                    Ok(Arc::new(RefreshingStore {
+                        builder,
+                        provider: self.provider.clone(),
+                        current: Mutex::new((Arc::new(store), expiry)),
+                    }))
+                }
+                None => Ok(Arc::new(with_credentials(builder, &self.credentials).build()?)),
+            },
+        }
+    }
+}
+
+fn with_credentials(builder: AmazonS3Builder, credentials: &Credentials) -> AmazonS3Builder {
+    let builder = builder
+        .with_access_key_id(credentials.access_key_id())
+        .with_secret_access_key(credentials.secret_access_key());
+    match credentials.session_token() {
+        Some(token) => builder.with_token(token),
+        None => builder,
+    }
+}
+
+/// S3 client for credentials object_store cannot refresh itself. Before each
+/// call it checks the expiry of the credentials the client was built with,
+/// and once they are about to run out it asks the SDK provider for new ones
+/// and builds a new client. Multipart writers keep the client they were
+/// started with.
+#[derive(Debug)]
+struct RefreshingStore {
+    /// Everything but the credentials
+    builder: AmazonS3Builder,
+    provider: SharedCredentialsProvider,
+    current: Mutex<(Arc<AmazonS3>, SystemTime)>,
+}
+
+impl RefreshingStore {
+    /// The client, rebuilt first if its credentials expire within REFRESH_MARGIN
+    async fn client(&self) -> object_store::Result<Arc<AmazonS3>> {
+        let mut current = self.current.lock().await;
+        if SystemTime::now() + REFRESH_MARGIN >= current.1 {
+            info!("Refreshing AWS credentials of the object_store client");
+            let credentials = self.provider.provide_credentials().await
+                .map_err(|e| object_store::Error::Generic { store: "S3", source: Box::new(e) })?;
+            let store = with_credentials(self.builder.clone(), &credentials).build()?;
+            // A provider that stops reporting an expiry is asked again next time
+            *current = (Arc::new(store), credentials.expiry().unwrap_or_else(SystemTime::now));
+        }
+        Ok(current.0.clone())
+    }
+}
+
+impl std::fmt::Display for RefreshingStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.current.try_lock() {
+            Ok(current) => write!(f, "RefreshingStore({})", current.0),
+            Err(_) => write!(f, "RefreshingStore"),
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for RefreshingStore {
+    async fn put(&self, location: &ObjectPath, bytes: Bytes) -> object_store::Result<()> {
+        self.client().await?.put(location, bytes).await
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &ObjectPath,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        self.client().await?.put_multipart(location).await
+    }
+
+    async fn abort_multipart(&self, location: &ObjectPath, multipart_id: &MultipartId) -> object_store::Result<()> {
+        self.client().await?.abort_multipart(location, multipart_id).await
+    }
+
+    async fn get(&self, location: &ObjectPath) -> object_store::Result<GetResult> {
+        self.client().await?.get(location).await
+    }
+
+    async fn get_range(&self, location: &ObjectPath, range: Range<usize>) -> object_store::Result<Bytes> {
+        self.client().await?.get_range(location, range).await
+    }
+
+    async fn head(&self, location: &ObjectPath) -> object_store::Result<ObjectMeta> {
+        self.client().await?.head(location).await
+    }
+
+    async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+        self.client().await?.delete(location).await
+    }
+
+    /// The listing borrows the client it runs on, which may be replaced
+    /// while it is consumed, so all pages are fetched up front
+    async fn list(
+        &self,
+        prefix: Option<&ObjectPath>,
+    ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+        let client = self.client().await?;
+        let objects: Vec<ObjectMeta> = client.list(prefix).await?.try_collect().await?;
+        Ok(stream::iter(objects.into_iter().map(Ok)).boxed())
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&ObjectPath>) -> object_store::Result<ListResult> {
+        self.client().await?.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.client().await?.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.client().await?.copy_if_not_exists(from, to).await
+    }
+}
+
+/// Walk the credential chain for `config`
+pub async fn resolve(config: &StorageConfig, provider_config: &ProviderConfig) -> Result<ResolvedCredentials> {
+    let mut candidates: Vec<(CredentialSource, SharedCredentialsProvider)> = Vec::new();
+    let mut tried = Vec::new();
+
+    if let Some(profile) = &config.profile {
+        // An explicitly requested profile must not silently fall back to another identity
+        candidates.push((
+            CredentialSource::Profile(profile.clone()),
+            SharedCredentialsProvider::new(ProfileFileCredentialsProvider::builder()
+                .configure(provider_config)
+                .profile_name(profile)
+                .build()),
+        ));
+    } else {
+        match (&config.access_key_id, &config.secret_access_key) {
+            (Some(key_id), Some(secret)) => candidates.push((
+                CredentialSource::Config,
+                SharedCredentialsProvider::new(Credentials::new(
+                    key_id,
+                    secret,
+                    config.session_token.clone(),
+                    None,
+                    "mytool-config",
+                )),
+            )),
+            (None, None) => tried.push(format!("{}: access_key_id/secret_access_key not set", CredentialSource::Config)),
+            _ => tried.push(format!("{}: access_key_id and secret_access_key must be set together", CredentialSource::Config)),
+        }
+
+        candidates.push((
+            CredentialSource::Environment,
+            SharedCredentialsProvider::new(EnvironmentVariableCredentialsProvider::new()),
+        ));
+        candidates.push((
+            CredentialSource::WebIdentity,
+            SharedCredentialsProvider::new(WebIdentityTokenCredentialsProvider::builder()
+                .configure(provider_config)
+                .build()),
+        ));
+        let default_profile = std::env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        candidates.push((
+            CredentialSource::Profile(default_profile),
+            SharedCredentialsProvider::new(ProfileFileCredentialsProvider::builder()
+                .configure(provider_config)
+                .build()),
+        ));
+        candidates.push((
+            CredentialSource::InstanceMetadata,
+            SharedCredentialsProvider::new(ImdsCredentialsProvider::builder()
+                .configure(provider_config)
+                .build()),
+        ));
+    }
+
+    for (source, provider) in candidates {
+        match provider.provide_credentials().await {
+            Ok(credentials) => {
+                info!("Using AWS credentials from {}", source);
+                return Ok(ResolvedCredentials { source, provider, credentials });
+            }
+            Err(e) => {
+                debug!("No AWS credentials from {}: {}", source, e);
+                tried.push(format!("{}: {}", source, e));
+            }
+        }
+    }
+
+    Err(ToolError::Credentials(format!(
+        "No AWS credentials found. Tried:\n  - {}",
+        tried.join("\n  - ")
+    )))
+}
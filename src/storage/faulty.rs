@@ -0,0 +1,222 @@
+//! Fault Injection Module
+//!
+//! Injects failures into a backend's requests, so the transfer, retry and
+//! sync logic can be exercised against misbehaving storage without touching
+//! a real cloud. FaultyStore wraps the backend's object_store client; S3
+//! also runs its AWS SDK requests (uploads, server-side copies, deletes)
+//! through the same FaultInjector.
+//!
+//! Faults (configured per remote under `fault_injection`):
+//! - latency_ms: fixed delay added before every request
+//! - throttle_probability: fail with an S3-style `503 SlowDown`
+//! - failure_probability: fail with a generic transient error
+//! - truncate_probability: cut GET streams off part-way through, or fail
+//!   ranged GETs after their data was fetched
+//! - seed: RNG seed; the same seed replays the same sequence of faults
+//!
+//! Injected errors surface as `object_store::Error::Generic` from the store
+//! "FaultInjection", exactly like transport errors from a real client.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use object_store::path::Path as ObjectPath;
+use object_store::{GetResult, ListResult, MultipartId, ObjectMeta, ObjectStore};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::config::{FaultInjection, StorageConfig};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tracing::{info, warn};
+
+/// A fault produced by FaultyStore
+#[derive(Debug)]
+enum InjectedFault {
+    SlowDown,
+    Failure(&'static str),
+    Truncated,
+}
+
+impl std::fmt::Display for InjectedFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InjectedFault::SlowDown => write!(f, "503 SlowDown: Please reduce your request rate (injected)"),
+            InjectedFault::Failure(op) => write!(f, "connection reset during {} (injected)", op),
+            InjectedFault::Truncated => write!(f, "response body truncated (injected)"),
+        }
+    }
+}
+
+impl std::error::Error for InjectedFault {}
+
+impl From<InjectedFault> for object_store::Error {
+    fn from(fault: InjectedFault) -> Self {
+        object_store::Error::Generic {
+            store: "FaultInjection",
+            source: Box::new(fault),
+        }
+    }
+}
+
+/// Source of the faults of one remote, shared by all of its clients
+#[derive(Debug)]
+pub struct FaultInjector {
+    faults: FaultInjection,
+    rng: Mutex<StdRng>,
+}
+
+impl FaultInjector {
+    pub fn new(faults: FaultInjection) -> Self {
+        let rng = match faults.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Self { faults, rng: Mutex::new(rng) }
+    }
+
+    /// The injector for a remote's `fault_injection`, if it has one
+    pub fn from_config(config: &StorageConfig) -> Option<Arc<Self>> {
+        config.fault_injection.as_ref().map(|faults| {
+            info!("Injecting faults into storage requests: {:?}", faults);
+            Arc::new(Self::new(faults.clone()))
+        })
+    }
+
+    fn roll(&self) -> f64 {
+        self.rng.lock().unwrap().gen::<f64>()
+    }
+
+    /// Apply latency, then possibly fail the request `op`
+    pub async fn inject(&self, op: &'static str) -> object_store::Result<()> {
+        if self.faults.latency_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(self.faults.latency_ms)).await;
+        }
+
+        let roll = self.roll();
+        if roll < self.faults.throttle_probability {
+            warn!("Injecting throttling into {}", op);
+            return Err(InjectedFault::SlowDown.into());
+        }
+        if roll < self.faults.throttle_probability + self.faults.failure_probability {
+            warn!("Injecting failure into {}", op);
+            return Err(InjectedFault::Failure(op).into());
+        }
+        Ok(())
+    }
+
+    /// Whether the response body of a GET should be cut off
+    fn truncates(&self) -> bool {
+        self.roll() < self.faults.truncate_probability
+    }
+}
+
+/// ObjectStore wrapper that injects latency and failures into another store
+#[derive(Debug)]
+pub struct FaultyStore {
+    inner: Arc<dyn ObjectStore>,
+    injector: Arc<FaultInjector>,
+}
+
+impl FaultyStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, injector: Arc<FaultInjector>) -> Self {
+        Self { inner, injector }
+    }
+
+    async fn inject(&self, op: &'static str) -> object_store::Result<()> {
+        self.injector.inject(op).await
+    }
+}
+
+impl std::fmt::Display for FaultyStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FaultyStore({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FaultyStore {
+    async fn put(&self, location: &ObjectPath, bytes: Bytes) -> object_store::Result<()> {
+        self.inject("put").await?;
+        self.inner.put(location, bytes).await
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &ObjectPath,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        self.inject("put_multipart").await?;
+        self.inner.put_multipart(location).await
+    }
+
+    async fn abort_multipart(&self, location: &ObjectPath, multipart_id: &MultipartId) -> object_store::Result<()> {
+        // Aborts are cleanup; failing them only leaks uploads without testing anything
+        self.inner.abort_multipart(location, multipart_id).await
+    }
+
+    async fn get(&self, location: &ObjectPath) -> object_store::Result<GetResult> {
+        self.inject("get").await?;
+        let result = self.inner.get(location).await?;
+
+        if !self.injector.truncates() {
+            return Ok(result);
+        }
+
+        warn!("Injecting truncated stream into get of {}", location);
+        // Deliver half of the first chunk, then fail like a dropped connection
+        let truncated = result.into_stream()
+            .take(1)
+            .map_ok(|chunk| chunk.slice(..chunk.len() / 2))
+            .chain(stream::once(async { Err(InjectedFault::Truncated.into()) }))
+            .boxed();
+        Ok(GetResult::Stream(truncated))
+    }
+
+    async fn get_range(&self, location: &ObjectPath, range: Range<usize>) -> object_store::Result<Bytes> {
+        self.inject("get_range").await?;
+        let bytes = self.inner.get_range(location, range).await?;
+
+        if !self.injector.truncates() {
+            return Ok(bytes);
+        }
+
+        // The body is collected before it is returned, so a dropped
+        // connection surfaces as an error rather than as fewer bytes
+        warn!("Injecting truncated stream into get_range of {}", location);
+        Err(InjectedFault::Truncated.into())
+    }
+
+    async fn head(&self, location: &ObjectPath) -> object_store::Result<ObjectMeta> {
+        self.inject("head").await?;
+        self.inner.head(location).await
+    }
+
+    async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+        self.inject("delete").await?;
+        self.inner.delete(location).await
+    }
+
+    async fn list(
+        &self,
+        prefix: Option<&ObjectPath>,
+    ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+        self.inject("list").await?;
+        self.inner.list(prefix).await
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&ObjectPath>) -> object_store::Result<ListResult> {
+        self.inject("list_with_delimiter").await?;
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.inject("copy").await?;
+        self.inner.copy(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        self.inject("copy_if_not_exists").await?;
+        self.inner.copy_if_not_exists(from, to).await
+    }
+}
@@ -0,0 +1,193 @@
+//! Transfer Filter Module
+//!
+//! Selects the files a recursive copy or sync works on, from the
+//! `--include`/`--exclude` globs and `--filter-from` rule files of the
+//! command. Patterns are matched against paths relative to the source or
+//! destination root, with `/` as separator on every provider:
+//! - a pattern without `/` matches a file or directory name at any depth
+//!   (`*.tmp`, `__pycache__`)
+//! - a pattern with `/` other than a trailing one matches from the root
+//!   (`checkpoints/*.pt`, `/notes.txt`)
+//! - a matching directory matches everything below it
+//! - `*` does not cross `/`; `**` does
+//!
+//! A path is selected unless it matches an exclude, and, if there are any
+//! includes, only if it matches one of them. Excludes win over includes.
+//!
+//! Filters apply to both sides of a transfer: files the filter leaves out
+//! are neither transferred nor deleted from the destination.
+//!
+//! Filter files hold one rule per line: `+ GLOB` includes, `- GLOB`
+//! excludes; blank lines and lines starting with `#` are ignored.
+
+use crate::error::{Result, ToolError};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+use tracing::{debug, error};
+
+#[derive(Debug)]
+pub struct Filter {
+    includes: Option<GlobSet>,
+    excludes: GlobSet,
+}
+
+impl Filter {
+    /// Build a filter from the command's patterns and the rules of
+    /// `filter_from`; None if there is nothing to filter
+    pub fn new(includes: &[String], excludes: &[String], filter_from: Option<&Path>) -> Result<Option<Self>> {
+        let mut includes = includes.to_vec();
+        let mut excludes = excludes.to_vec();
+        if let Some(path) = filter_from {
+            read_rules(path, &mut includes, &mut excludes)?;
+        }
+        if includes.is_empty() && excludes.is_empty() {
+            return Ok(None);
+        }
+        debug!("Filtering with includes {:?} and excludes {:?}", includes, excludes);
+
+        Ok(Some(Self {
+            includes: if includes.is_empty() { None } else { Some(build_set(&includes)?) },
+            excludes: build_set(&excludes)?,
+        }))
+    }
+
+    /// Whether the file at `relative_path` is transferred
+    pub fn matches(&self, relative_path: &str) -> bool {
+        let path = relative_path.trim_start_matches('/');
+        if self.excludes.is_match(path) {
+            return false;
+        }
+        match &self.includes {
+            Some(includes) => includes.is_match(path),
+            None => true,
+        }
+    }
+
+    /// Whether everything below the directory at `relative_path` is left
+    /// out, so listings need not descend into it
+    pub fn excludes_dir(&self, relative_path: &str) -> bool {
+        self.excludes.is_match(relative_path.trim_start_matches('/'))
+    }
+}
+
+/// Add the rules of a filter file to `includes` and `excludes`
+fn read_rules(path: &Path, includes: &mut Vec<String>, excludes: &mut Vec<String>) -> Result<()> {
+    let rules = std::fs::read_to_string(path)
+        .map_err(|e| {
+            error!("Error reading filter file {}: {}", path.display(), e);
+            ToolError::Io(e)
+        })?;
+
+    for (number, line) in rules.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(' ') {
+            Some(("+", pattern)) => includes.push(pattern.trim().to_string()),
+            Some(("-", pattern)) => excludes.push(pattern.trim().to_string()),
+            _ => {
+                return Err(ToolError::Config(format!(
+                    "{}:{}: expected `+ GLOB` or `- GLOB`, got `{}`",
+                    path.display(),
+                    number + 1,
+                    line
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compile patterns into globs that match the path itself and everything
+/// below it, anywhere in the tree unless the pattern contains a `/`
+fn build_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        let pattern = pattern.trim_start_matches('/');
+        let pattern = if anchored { pattern.to_string() } else { format!("**/{}", pattern) };
+        builder.add(glob(&pattern)?);
+        builder.add(glob(&format!("{}/**", pattern))?);
+    }
+    builder.build()
+        .map_err(|e| ToolError::Config(format!("Invalid filter pattern: {}", e)))
+}
+
+fn glob(pattern: &str) -> Result<Glob> {
+    GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| ToolError::Config(format!("Invalid filter pattern `{}`: {}", pattern, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(includes: &[&str], excludes: &[&str]) -> Filter {
+        let strings = |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        Filter::new(&strings(includes), &strings(excludes), None).unwrap().unwrap()
+    }
+
+    #[test]
+    fn no_patterns_means_no_filter() {
+        assert!(Filter::new(&[], &[], None).unwrap().is_none());
+    }
+
+    #[test]
+    fn unanchored_patterns_match_names_at_any_depth() {
+        let filter = filter(&[], &["*.tmp", "__pycache__"]);
+        assert!(!filter.matches("scratch.tmp"));
+        assert!(!filter.matches("runs/exp42/scratch.tmp"));
+        assert!(!filter.matches("src/__pycache__/model.cpython-311.pyc"));
+        assert!(filter.excludes_dir("src/__pycache__"));
+        assert!(filter.matches("runs/exp42/model.pt"));
+        assert!(!filter.excludes_dir("runs"));
+    }
+
+    #[test]
+    fn patterns_with_a_slash_match_from_the_root() {
+        let filter = filter(&["checkpoints/*.pt", "/notes.txt"], &[]);
+        assert!(filter.matches("checkpoints/step-100.pt"));
+        assert!(filter.matches("notes.txt"));
+        assert!(!filter.matches("runs/notes.txt"));
+        assert!(!filter.matches("runs/checkpoints/step-100.pt"));
+    }
+
+    #[test]
+    fn only_double_star_crosses_directories() {
+        assert!(!filter(&["checkpoints/*.pt"], &[]).matches("checkpoints/old/step-50.pt"));
+        assert!(filter(&["checkpoints/**/*.pt"], &[]).matches("checkpoints/old/step-50.pt"));
+    }
+
+    #[test]
+    fn excludes_win_over_includes() {
+        let filter = filter(&["checkpoints"], &["*.tmp"]);
+        assert!(filter.matches("checkpoints/step-100.pt"));
+        assert!(!filter.matches("checkpoints/step-100.pt.tmp"));
+        assert!(!filter.matches("logs/train.log"));
+    }
+
+    #[test]
+    fn reads_rules_from_a_filter_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"# checkpoints only\n+ checkpoints/\n\n- *.tmp\n").unwrap();
+        let filter = Filter::new(&[], &[], Some(file.path())).unwrap().unwrap();
+        assert!(filter.matches("checkpoints/step-100.pt"));
+        assert!(!filter.matches("checkpoints/step-100.tmp"));
+        assert!(!filter.matches("config.json"));
+    }
+
+    #[test]
+    fn rejects_malformed_rules() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"+ *.pt\ncheckpoints\n").unwrap();
+        match Filter::new(&[], &[], Some(file.path())) {
+            Err(ToolError::Config(message)) => assert!(message.contains(":2: expected"), "{}", message),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(Filter::new(&["[".to_string()], &[], None), Err(ToolError::Config(_))));
+    }
+}
@@ -0,0 +1,117 @@
+//! Google Cloud Storage Implementation Module
+//!
+//! This module builds a GCS client on top of object_store's GoogleCloudStorage
+//! and plugs it into the StorageBackend trait.
+//!
+//! Credentials:
+//! - `service_account_path`: path to a service-account JSON key file
+//! - `service_account_key`: the same JSON key inline in the config
+//! - neither: application default credentials (GOOGLE_APPLICATION_CREDENTIALS,
+//!   gcloud login or the instance metadata server)
+//!
+//! Endpoint:
+//! When `endpoint` is set (e.g. a local fake-gcs-server), requests go to that
+//! base URL instead of storage.googleapis.com. Without service-account
+//! credentials the emulator is used unauthenticated.
+
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::{ClientOptions, ObjectStore};
+use crate::error::{Result, ToolError};
+use crate::config::{StorageConfig, TransferOptions};
+use std::sync::Arc;
+use tracing::info;
+use async_trait::async_trait;
+use super::faulty::FaultInjector;
+use super::{retry, wrap_store, StorageBackend, TransferContext};
+
+pub struct GcsStorage {
+    store: Arc<dyn ObjectStore>,
+    transfer: TransferOptions,
+    context: TransferContext,
+}
+
+impl GcsStorage {
+    pub fn new(config: &StorageConfig, transfer: &TransferOptions, context: &TransferContext) -> Result<Self> {
+        let bucket = config.bucket.clone()
+            .ok_or_else(|| ToolError::Config("GCS bucket not specified".into()))?;
+
+        info!("Building GCS storage with bucket: {}", bucket);
+
+        let mut builder = GoogleCloudStorageBuilder::new()
+            .with_bucket_name(&bucket);
+
+        if let Some(key) = Self::service_account_key(config)? {
+            builder = builder.with_service_account_key(key);
+        }
+
+        let allow_http = config.endpoint.as_ref()
+            .is_some_and(|endpoint| endpoint.starts_with("http://"));
+        builder = builder.with_client_options(ClientOptions::new()
+            .with_allow_http(allow_http)
+            .with_allow_invalid_certificates(config.allow_insecure));
+
+        let store = builder
+            .with_retry(retry::store_retry_config(&retry::RetryPolicy::from_options(transfer)))
+            .build()?;
+
+        info!("Successfully initialized GCS storage");
+
+        Ok(Self {
+            store: wrap_store(Arc::new(store), FaultInjector::from_config(config), transfer),
+            transfer: transfer.clone(),
+            context: context.clone(),
+        })
+    }
+
+    /// Service-account JSON to hand to object_store, with `endpoint` applied.
+    ///
+    /// object_store reads the GCS base URL from the `gcs_base_url` field of the
+    /// service-account JSON, so a configured endpoint is injected there. An
+    /// endpoint without credentials targets an emulator with OAuth disabled.
+    fn service_account_key(config: &StorageConfig) -> Result<Option<String>> {
+        let key = match (&config.service_account_path, &config.service_account_key) {
+            (Some(path), _) => Some(std::fs::read_to_string(path)
+                .map_err(|e| ToolError::Config(format!(
+                    "Failed to read GCS service account file {}: {}", path.display(), e
+                )))?),
+            (None, Some(key)) => Some(key.clone()),
+            (None, None) => None,
+        };
+
+        let mut json = match (key, &config.endpoint) {
+            (Some(key), _) => serde_json::from_str::<serde_json::Value>(&key)
+                .map_err(|e| ToolError::Config(format!("Invalid GCS service account JSON: {}", e)))?,
+            (None, Some(_)) => serde_json::json!({
+                "private_key": "",
+                "client_email": "",
+                "disable_oauth": true,
+            }),
+            (None, None) => return Ok(None),
+        };
+
+        if let Some(endpoint) = &config.endpoint {
+            json["gcs_base_url"] = serde_json::Value::String(endpoint.trim_end_matches('/').to_string());
+        }
+
+        Ok(Some(json.to_string()))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for GcsStorage {
+    fn provider_name(&self) -> &'static str {
+        "GCS"
+    }
+
+    fn store(&self) -> &Arc<dyn ObjectStore> {
+        &self.store
+    }
+
+    fn transfer_options(&self) -> &TransferOptions {
+        &self.transfer
+    }
+
+    fn context(&self) -> &TransferContext {
+        &self.context
+    }
+}
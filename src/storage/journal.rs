@@ -0,0 +1,428 @@
+//! Transfer Journal Module
+//!
+//! Records the progress of a copy or sync on disk so an interrupted run can
+//! continue where it stopped instead of starting over:
+//! - files that were transferred completely
+//! - multipart uploads in progress, with their upload ID and finished parts
+//! - byte ranges of large downloads already written to the partial file
+//!
+//! Every record carries a fingerprint (size and modification time) of the
+//! source it was made for, so progress on a file that has changed since is
+//! ignored and the file is transferred again. `--restart` discards the
+//! journal, and the multipart uploads it recorded are aborted.
+//!
+//! The journal is an append-only JSON-lines file under
+//! `<data dir>/mytool/journals` (or `transfer_options.journal_dir`), named
+//! after a hash of the operation. Records are written as soon as each step
+//! finishes, and a torn last line left by a killed process is skipped on
+//! replay. The file is removed once the operation succeeds.
+
+use crate::error::{Result, ToolError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// What to do with a journal left behind by an earlier run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    /// Continue from the journal if there is one
+    Auto,
+    /// Continue from the journal, failing if there is none (`--resume`)
+    Required,
+    /// Discard the journal and start over (`--restart`)
+    Restart,
+}
+
+/// Identifies the version of a source file or object a record was made for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: u64,
+}
+
+impl Fingerprint {
+    pub fn new(size: u64, modified: SystemTime) -> Self {
+        let modified = modified.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self { size, modified }
+    }
+
+    pub fn from_metadata(metadata: &std::fs::Metadata) -> Self {
+        Self::new(metadata.len(), metadata.modified().unwrap_or(UNIX_EPOCH))
+    }
+}
+
+/// A multipart upload that can be continued
+#[derive(Debug, Clone)]
+pub struct PendingUpload {
+    pub upload_id: String,
+    pub part_size: u64,
+    /// The finished parts, by part number
+    pub parts: BTreeMap<i32, UploadedPart>,
+}
+
+/// A part the service has acknowledged
+#[derive(Debug, Clone)]
+pub struct UploadedPart {
+    pub etag: String,
+    /// Base64 CRC32C the part was sent with, if any
+    pub crc32c: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum Record {
+    Operation { operation: String },
+    Done { key: String, source: Fingerprint },
+    Upload { key: String, source: Fingerprint, upload_id: String, part_size: u64 },
+    Part {
+        key: String,
+        upload_id: String,
+        number: i32,
+        etag: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc32c: Option<String>,
+    },
+    Range { key: String, source: Fingerprint, start: u64, end: u64 },
+}
+
+#[derive(Debug, Default)]
+struct State {
+    done: HashMap<String, Fingerprint>,
+    uploads: HashMap<String, (Fingerprint, PendingUpload)>,
+    ranges: HashMap<String, (Fingerprint, Vec<Range<u64>>)>,
+}
+
+impl State {
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Operation { .. } => {}
+            Record::Done { key, source } => {
+                self.uploads.remove(&key);
+                self.ranges.remove(&key);
+                self.done.insert(key, source);
+            }
+            Record::Upload { key, source, upload_id, part_size } => {
+                self.done.remove(&key);
+                self.uploads.insert(key, (source, PendingUpload { upload_id, part_size, parts: BTreeMap::new() }));
+            }
+            Record::Part { key, upload_id, number, etag, crc32c } => {
+                if let Some((_, upload)) = self.uploads.get_mut(&key) {
+                    if upload.upload_id == upload_id {
+                        upload.parts.insert(number, UploadedPart { etag, crc32c });
+                    }
+                }
+            }
+            Record::Range { key, source, start, end } => {
+                let entry = self.ranges.entry(key).or_insert_with(|| (source, Vec::new()));
+                if entry.0 != source {
+                    *entry = (source, Vec::new());
+                }
+                entry.1.push(start..end);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    state: Mutex<State>,
+    /// Multipart uploads (key, upload ID) of the run a restart discarded
+    abandoned: Mutex<Vec<(String, String)>>,
+}
+
+impl Journal {
+    /// Open the journal of `operation` in `dir`, replaying an earlier run's
+    /// records unless `resume` is Restart
+    pub fn open(dir: &Path, operation: &str, resume: Resume) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| ToolError::Config(format!("Cannot create journal directory {}: {}", dir.display(), e)))?;
+        let path = dir.join(format!("{:016x}.jsonl", fnv1a(operation.as_bytes())));
+
+        let mut state = State::default();
+        let mut resumed = false;
+        let mut abandoned = Vec::new();
+        if path.exists() && resume == Resume::Restart {
+            // The uploads the earlier run left open are aborted by the backend
+            let mut discarded = State::default();
+            if Self::replay(&path, operation, &mut discarded)? {
+                abandoned = discarded.uploads.into_iter()
+                    .map(|(key, (_, upload))| (key, upload.upload_id))
+                    .collect();
+            }
+        } else if path.exists() {
+            resumed = Self::replay(&path, operation, &mut state)?;
+        }
+        if !resumed && resume == Resume::Required {
+            return Err(ToolError::InvalidOperation(format!("There is no interrupted transfer to resume for {}", operation)));
+        }
+
+        let mut options = OpenOptions::new();
+        if resumed {
+            options.append(true);
+        } else {
+            options.write(true).create(true).truncate(true);
+        }
+        let file = options.open(&path)
+            .map_err(|e| ToolError::Config(format!("Cannot open journal {}: {}", path.display(), e)))?;
+
+        let journal = Self {
+            path,
+            file: Mutex::new(file),
+            state: Mutex::new(state),
+            abandoned: Mutex::new(abandoned),
+        };
+        if resumed {
+            // Start on a fresh line in case the last run died mid-record
+            journal.file.lock().unwrap().write_all(b"\n")?;
+            let state = journal.state.lock().unwrap();
+            info!(
+                "Resuming from journal {}: {} files done, {} uploads and {} downloads in progress",
+                journal.path.display(),
+                state.done.len(),
+                state.uploads.len(),
+                state.ranges.len()
+            );
+        } else {
+            journal.append(&Record::Operation { operation: operation.to_string() })?;
+        }
+        Ok(journal)
+    }
+
+    /// Load the records of `path` into `state`; false if the file belongs to
+    /// another operation or holds no records
+    fn replay(path: &Path, operation: &str, state: &mut State) -> Result<bool> {
+        let file = File::open(path)
+            .map_err(|e| ToolError::Config(format!("Cannot read journal {}: {}", path.display(), e)))?;
+        let mut lines = BufReader::new(file).lines();
+
+        match lines.next().transpose()?.map(|line| serde_json::from_str::<Record>(&line)) {
+            Some(Ok(Record::Operation { operation: recorded })) if recorded == operation => {}
+            _ => {
+                warn!("Ignoring journal {} written for another operation", path.display());
+                return Ok(false);
+            }
+        }
+
+        for line in lines {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => state.apply(record),
+                Err(e) => warn!("Skipping unreadable journal record in {}: {}", path.display(), e),
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `key` was transferred completely from a source matching `source`
+    pub fn is_done(&self, key: &str, source: &Fingerprint) -> bool {
+        self.state.lock().unwrap().done.get(key) == Some(source)
+    }
+
+    pub fn record_done(&self, key: &str, source: Fingerprint) -> Result<()> {
+        self.update(Record::Done { key: key.to_string(), source })
+    }
+
+    /// The open multipart upload of `key` and the source it was started from
+    pub fn pending_upload(&self, key: &str) -> Option<(Fingerprint, PendingUpload)> {
+        self.state.lock().unwrap().uploads.get(key).cloned()
+    }
+
+    /// Take the multipart uploads (key, upload ID) left open by the run a
+    /// restart discarded; they are handed out once
+    pub fn take_abandoned_uploads(&self) -> Vec<(String, String)> {
+        std::mem::take(&mut *self.abandoned.lock().unwrap())
+    }
+
+    pub fn record_upload(&self, key: &str, source: Fingerprint, upload_id: &str, part_size: u64) -> Result<()> {
+        self.update(Record::Upload {
+            key: key.to_string(),
+            source,
+            upload_id: upload_id.to_string(),
+            part_size,
+        })
+    }
+
+    pub fn record_part(&self, key: &str, upload_id: &str, number: i32, part: &UploadedPart) -> Result<()> {
+        self.update(Record::Part {
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            number,
+            etag: part.etag.clone(),
+            crc32c: part.crc32c.clone(),
+        })
+    }
+
+    /// Byte ranges of `key` already downloaded from a source matching `source`
+    pub fn downloaded_ranges(&self, key: &str, source: &Fingerprint) -> Vec<Range<u64>> {
+        match self.state.lock().unwrap().ranges.get(key) {
+            Some((fingerprint, ranges)) if fingerprint == source => ranges.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn record_range(&self, key: &str, source: Fingerprint, range: Range<u64>) -> Result<()> {
+        self.update(Record::Range {
+            key: key.to_string(),
+            source,
+            start: range.start,
+            end: range.end,
+        })
+    }
+
+    /// Remove the journal once the operation has succeeded
+    pub fn finish(&self) -> Result<()> {
+        std::fs::remove_file(&self.path).map_err(ToolError::Io)
+    }
+
+    fn update(&self, record: Record) -> Result<()> {
+        self.append(&record)?;
+        self.state.lock().unwrap().apply(record);
+        Ok(())
+    }
+
+    fn append(&self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_vec(record)
+            .map_err(|e| ToolError::Config(format!("Cannot encode journal record: {}", e)))?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+            .map_err(|e| {
+                warn!("Could not write journal {}: {}", self.path.display(), e);
+                ToolError::Io(e)
+            })
+    }
+}
+
+/// Directory journals are kept in unless `transfer_options.journal_dir` is set
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|mut dir| {
+        dir.push("mytool");
+        dir.push("journals");
+        dir
+    })
+}
+
+/// Stable 64-bit FNV-1a hash, used to name journal and baseline files
+pub(super) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPERATION: &str = "copy /data/run1 s3://bucket/run1";
+
+    fn fingerprint(size: u64) -> Fingerprint {
+        Fingerprint { size, modified: 1_700_000_000_000_000_000 }
+    }
+
+    fn part(etag: &str) -> UploadedPart {
+        UploadedPart { etag: etag.to_string(), crc32c: Some("AAAAAA==".to_string()) }
+    }
+
+    #[test]
+    fn replays_the_progress_of_an_interrupted_run() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+            journal.record_done("a.bin", fingerprint(10)).unwrap();
+            journal.record_upload("b.bin", fingerprint(20), "upload-1", 8).unwrap();
+            journal.record_part("b.bin", "upload-1", 1, &part("etag-1")).unwrap();
+            journal.record_part("b.bin", "upload-0", 2, &part("stale")).unwrap();
+            journal.record_range("c.bin", fingerprint(30), 0..8).unwrap();
+            journal.record_range("c.bin", fingerprint(30), 16..24).unwrap();
+        }
+
+        let journal = Journal::open(dir.path(), OPERATION, Resume::Required).unwrap();
+        assert!(journal.is_done("a.bin", &fingerprint(10)));
+        assert!(!journal.is_done("a.bin", &fingerprint(11)));
+
+        let (source, upload) = journal.pending_upload("b.bin").unwrap();
+        assert_eq!(source, fingerprint(20));
+        assert_eq!(upload.upload_id, "upload-1");
+        // Parts of other uploads of the same key are ignored
+        assert_eq!(upload.parts.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(upload.parts[&1].crc32c.as_deref(), Some("AAAAAA=="));
+
+        assert_eq!(journal.downloaded_ranges("c.bin", &fingerprint(30)), [0..8, 16..24]);
+        assert!(journal.downloaded_ranges("c.bin", &fingerprint(31)).is_empty());
+        assert!(journal.take_abandoned_uploads().is_empty());
+    }
+
+    #[test]
+    fn a_finished_file_drops_its_pending_upload() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+            journal.record_upload("b.bin", fingerprint(20), "upload-1", 8).unwrap();
+            journal.record_done("b.bin", fingerprint(20)).unwrap();
+        }
+        let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+        assert!(journal.pending_upload("b.bin").is_none());
+        assert!(journal.is_done("b.bin", &fingerprint(20)));
+    }
+
+    #[test]
+    fn skips_a_torn_last_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = {
+            let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+            journal.record_done("a.bin", fingerprint(10)).unwrap();
+            journal.path().to_path_buf()
+        };
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"record\":\"done\",\"key\":\"b.b").unwrap();
+
+        let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+        assert!(journal.is_done("a.bin", &fingerprint(10)));
+        // Records written after the torn line are still read back
+        journal.record_done("c.bin", fingerprint(30)).unwrap();
+        drop(journal);
+        let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+        assert!(journal.is_done("c.bin", &fingerprint(30)));
+    }
+
+    #[test]
+    fn restart_discards_progress_and_hands_out_open_uploads() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+            journal.record_done("a.bin", fingerprint(10)).unwrap();
+            journal.record_upload("b.bin", fingerprint(20), "upload-1", 8).unwrap();
+        }
+
+        let journal = Journal::open(dir.path(), OPERATION, Resume::Restart).unwrap();
+        assert!(!journal.is_done("a.bin", &fingerprint(10)));
+        assert!(journal.pending_upload("b.bin").is_none());
+        assert_eq!(journal.take_abandoned_uploads(), [("b.bin".to_string(), "upload-1".to_string())]);
+        assert!(journal.take_abandoned_uploads().is_empty());
+    }
+
+    #[test]
+    fn resume_requires_a_journal_of_the_same_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(Journal::open(dir.path(), OPERATION, Resume::Required), Err(ToolError::InvalidOperation(_))));
+
+        let journal = Journal::open(dir.path(), OPERATION, Resume::Auto).unwrap();
+        journal.record_done("a.bin", fingerprint(10)).unwrap();
+        journal.finish().unwrap();
+        assert!(matches!(Journal::open(dir.path(), OPERATION, Resume::Required), Err(ToolError::InvalidOperation(_))));
+    }
+}
@@ -0,0 +1,105 @@
+//! Local Filesystem Storage Implementation Module
+//!
+//! This module serves `file://` URLs through object_store's LocalFileSystem,
+//! so local and network filesystems (NFS, Lustre) get exactly the same copy
+//! and sync semantics as cloud buckets.
+//!
+//! Layout:
+//! - The root directory is the configured `bucket`, or `/` when unset
+//! - Object keys are paths relative to that root
+//!
+//! Writes go through object_store, which stages each file and renames it
+//! into place, so readers never observe partially written files. Uploads
+//! keep the source file's mtime and mode on the written file. Copies
+//! within the root create the destination's parent directories first, which
+//! object_store's copy does not do.
+
+use object_store::local::LocalFileSystem;
+use object_store::ObjectStore;
+use crate::error::{Result, ToolError};
+use crate::config::{StorageConfig, TransferOptions};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, error};
+use async_trait::async_trait;
+use super::metadata::FileMetadata;
+use super::faulty::FaultInjector;
+use super::{transfer, wrap_store, StorageBackend, TransferContext};
+
+pub struct LocalStorage {
+    root: PathBuf,
+    store: Arc<dyn ObjectStore>,
+    transfer: TransferOptions,
+    context: TransferContext,
+}
+
+impl LocalStorage {
+    pub fn new(config: &StorageConfig, transfer: &TransferOptions, context: &TransferContext) -> Result<Self> {
+        let root = config.bucket.clone().unwrap_or_else(|| "/".to_string());
+
+        info!("Building local storage rooted at: {}", root);
+
+        std::fs::create_dir_all(&root)
+            .map_err(|e| {
+                error!("Error creating storage root {}: {}", root, e);
+                ToolError::Io(e)
+            })?;
+
+        let store = LocalFileSystem::new_with_prefix(&root)?;
+
+        info!("Successfully initialized local storage");
+
+        Ok(Self {
+            root: PathBuf::from(root),
+            store: wrap_store(Arc::new(store), FaultInjector::from_config(config), transfer),
+            transfer: transfer.clone(),
+            context: context.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    fn provider_name(&self) -> &'static str {
+        "local filesystem"
+    }
+
+    fn store(&self) -> &Arc<dyn ObjectStore> {
+        &self.store
+    }
+
+    fn transfer_options(&self) -> &TransferOptions {
+        &self.transfer
+    }
+
+    fn context(&self) -> &TransferContext {
+        &self.context
+    }
+
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<()> {
+        transfer::upload_file(self, local_path, remote_path).await?;
+        FileMetadata::from_file(local_path).await?
+            .apply(&self.root.join(remote_path))
+            .await
+    }
+
+    /// The written file itself carries the attributes
+    async fn file_metadata(&self, path: &str) -> Result<Option<FileMetadata>> {
+        Ok(Some(FileMetadata::from_file(&self.root.join(path)).await?))
+    }
+
+    async fn copy_from(&self, source: &dyn StorageBackend, src_path: &str, dest_path: &str) -> Result<()> {
+        if let Some(parent) = self.root.join(dest_path).parent() {
+            tokio::fs::create_dir_all(parent).await
+                .map_err(|e| {
+                    error!("Error creating directory {}: {}", parent.display(), e);
+                    ToolError::Io(e)
+                })?;
+        }
+        transfer::copy_from(self, source, src_path, dest_path).await
+    }
+
+    async fn copy_object(&self, src_path: &str, dest_path: &str) -> Result<()> {
+        self.copy_from(self, src_path, dest_path).await
+    }
+}
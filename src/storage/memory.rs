@@ -0,0 +1,51 @@
+//! In-Memory Storage Implementation Module
+//!
+//! This module serves `mem://` URLs through object_store's InMemory store.
+//! Nothing is persisted: the contents live only as long as the process, which
+//! makes it a deterministic, dependency-free backend for exercising transfer
+//! and sync logic (optionally combined with fault injection).
+
+use object_store::memory::InMemory;
+use object_store::ObjectStore;
+use crate::config::{StorageConfig, TransferOptions};
+use std::sync::Arc;
+use tracing::info;
+use async_trait::async_trait;
+use super::faulty::FaultInjector;
+use super::{wrap_store, StorageBackend, TransferContext};
+
+pub struct MemoryStorage {
+    store: Arc<dyn ObjectStore>,
+    transfer: TransferOptions,
+    context: TransferContext,
+}
+
+impl MemoryStorage {
+    pub fn new(config: &StorageConfig, transfer: &TransferOptions, context: &TransferContext) -> Self {
+        info!("Building in-memory storage");
+        Self {
+            store: wrap_store(Arc::new(InMemory::new()), FaultInjector::from_config(config), transfer),
+            transfer: transfer.clone(),
+            context: context.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    fn provider_name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn store(&self) -> &Arc<dyn ObjectStore> {
+        &self.store
+    }
+
+    fn transfer_options(&self) -> &TransferOptions {
+        &self.transfer
+    }
+
+    fn context(&self) -> &TransferContext {
+        &self.context
+    }
+}
@@ -0,0 +1,207 @@
+//! File Metadata Module
+//!
+//! Carries the attributes of a local file through object storage, so that
+//! downloads restore them and syncs can compare against the source file
+//! rather than the time an object happened to be uploaded.
+//!
+//! Stored as object user metadata (`x-amz-meta-*` on S3):
+//! - mytool-mtime: modification time as `<seconds>.<nanoseconds>`
+//! - mytool-mode: POSIX permission bits in octal
+//! - mytool-sha256: hex SHA-256 of the content
+//!
+//! Providers without user metadata support return None from
+//! `StorageBackend::file_metadata`; sync then falls back to the object's
+//! own modification time. Providers that report ETags (S3) also return the
+//! object's ETag, which is read from the object rather than stored.
+
+use crate::error::{Result, ToolError};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tracing::{debug, error};
+
+const MTIME_KEY: &str = "mytool-mtime";
+const MODE_KEY: &str = "mytool-mode";
+const SHA256_KEY: &str = "mytool-sha256";
+
+/// Bytes read at a time while hashing a file
+pub(super) const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileMetadata {
+    pub mtime: Option<SystemTime>,
+    pub mode: Option<u32>,
+    pub sha256: Option<String>,
+    /// The object's ETag, without quotes; never stored
+    pub etag: Option<String>,
+}
+
+impl FileMetadata {
+    /// Modification time and permissions of a local file, without hashing it
+    pub async fn from_file(path: &Path) -> Result<Self> {
+        let metadata = tokio::fs::metadata(path).await
+            .map_err(|e| {
+                error!("Error getting file metadata of {}: {}", path.display(), e);
+                ToolError::Io(e)
+            })?;
+        Ok(Self {
+            mtime: metadata.modified().ok(),
+            mode: Some(metadata.permissions().mode() & 0o7777),
+            sha256: None,
+            etag: None,
+        })
+    }
+
+    /// Modification time, permissions and content hash of a local file
+    pub async fn from_file_with_hash(path: &Path) -> Result<Self> {
+        let mut metadata = Self::from_file(path).await?;
+        metadata.sha256 = Some(sha256_file(path).await?);
+        Ok(metadata)
+    }
+
+    /// Read the attributes stored with an object; unknown keys are ignored
+    pub fn from_user_metadata(user_metadata: &HashMap<String, String>) -> Option<Self> {
+        let metadata = Self {
+            mtime: user_metadata.get(MTIME_KEY).and_then(|value| parse_mtime(value)),
+            mode: user_metadata.get(MODE_KEY).and_then(|value| u32::from_str_radix(value, 8).ok()),
+            sha256: user_metadata.get(SHA256_KEY).cloned(),
+            etag: None,
+        };
+        if metadata == Self::default() {
+            None
+        } else {
+            Some(metadata)
+        }
+    }
+
+    /// Attach the ETag an object was reported with, stripping its quotes
+    pub fn with_etag(mut self, etag: Option<&str>) -> Self {
+        self.etag = etag.map(|etag| etag.trim_matches('"').to_string());
+        self
+    }
+
+    /// The attributes as object user metadata
+    pub fn to_user_metadata(&self) -> HashMap<String, String> {
+        let mut user_metadata = HashMap::new();
+        if let Some(mtime) = self.mtime.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+            user_metadata.insert(MTIME_KEY.to_string(), format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos()));
+        }
+        if let Some(mode) = self.mode {
+            user_metadata.insert(MODE_KEY.to_string(), format!("{:o}", mode));
+        }
+        if let Some(sha256) = &self.sha256 {
+            user_metadata.insert(SHA256_KEY.to_string(), sha256.clone());
+        }
+        user_metadata
+    }
+
+    /// Set the modification time and permissions of a local file. The mtime
+    /// goes first, as setting it needs the file opened for writing, which
+    /// the restored permissions may no longer allow.
+    pub async fn apply(&self, path: &Path) -> Result<()> {
+        let attributes = self.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            if let Some(mtime) = attributes.mtime {
+                std::fs::File::options().write(true).open(&path)
+                    .and_then(|file| file.set_modified(mtime))
+                    .map_err(|e| {
+                        error!("Error setting modification time of {}: {}", path.display(), e);
+                        ToolError::Io(e)
+                    })?;
+            }
+            if let Some(mode) = attributes.mode {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+                    .map_err(|e| {
+                        error!("Error setting permissions of {}: {}", path.display(), e);
+                        ToolError::Io(e)
+                    })?;
+            }
+            debug!("Restored attributes of {}: {:?}", path.display(), attributes);
+            Ok(())
+        }).await
+            .map_err(|e| ToolError::Io(std::io::Error::other(e)))?
+    }
+}
+
+/// Hex SHA-256 of a local file's content
+pub async fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = tokio::fs::File::open(path).await
+        .map_err(|e| {
+            error!("Error opening file {}: {}", path.display(), e);
+            ToolError::Io(e)
+        })?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+    loop {
+        let read = file.read(&mut buffer).await
+            .map_err(|e| {
+                error!("Error reading file {}: {}", path.display(), e);
+                ToolError::Io(e)
+            })?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Parse a stored `<seconds>.<nanoseconds>` mtime; None for values that are
+/// malformed or do not fit a SystemTime, as they come from the object
+fn parse_mtime(value: &str) -> Option<SystemTime> {
+    let (secs, nanos) = value.split_once('.').unwrap_or((value, "0"));
+    let nanos: u32 = nanos.parse().ok()?;
+    if nanos >= 1_000_000_000 {
+        return None;
+    }
+    UNIX_EPOCH.checked_add(Duration::new(secs.parse().ok()?, nanos))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stored_mtimes() {
+        assert_eq!(parse_mtime("1700000000.000000123"), Some(UNIX_EPOCH + Duration::new(1_700_000_000, 123)));
+        assert_eq!(parse_mtime("1700000000"), Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+    }
+
+    #[test]
+    fn rejects_malformed_and_overflowing_mtimes() {
+        assert_eq!(parse_mtime("1700000000.1000000000"), None);
+        assert_eq!(parse_mtime(&format!("{}.0", u64::MAX)), None);
+        assert_eq!(parse_mtime("-1.0"), None);
+        assert_eq!(parse_mtime("yesterday"), None);
+        assert_eq!(parse_mtime(""), None);
+    }
+
+    #[test]
+    fn user_metadata_round_trips() {
+        let metadata = FileMetadata {
+            mtime: Some(UNIX_EPOCH + Duration::new(1_700_000_000, 5)),
+            mode: Some(0o640),
+            sha256: Some("ab".repeat(32)),
+            etag: None,
+        };
+        let stored = metadata.to_user_metadata();
+        assert_eq!(stored["mytool-mtime"], "1700000000.000000005");
+        assert_eq!(stored["mytool-mode"], "640");
+        assert_eq!(FileMetadata::from_user_metadata(&stored), Some(metadata));
+    }
+
+    #[test]
+    fn unknown_or_unreadable_user_metadata_is_ignored() {
+        let mut stored = HashMap::new();
+        stored.insert("owner".to_string(), "ml-team".to_string());
+        assert_eq!(FileMetadata::from_user_metadata(&stored), None);
+
+        stored.insert(MTIME_KEY.to_string(), "99999999999999999999999.0".to_string());
+        stored.insert(MODE_KEY.to_string(), "rw-r--r--".to_string());
+        assert_eq!(FileMetadata::from_user_metadata(&stored), None);
+    }
+}
@@ -1 +1,350 @@
-pub mod s3;
\ No newline at end of file
+//! Storage Backend Module
+//!
+//! This module defines the interface every cloud storage provider implements
+//! and selects the concrete implementation from the configuration.
+//!
+//! Components:
+//! - StorageBackend trait: file, directory and sync operations
+//! - build_backend: picks the implementation from StorageConfig.provider
+//! - s3: AWS S3 implementation
+//! - gcs: Google Cloud Storage implementation
+//! - azure: Azure Blob Storage implementation
+//! - local: local/network filesystem implementation for file:// URLs
+//! - memory: in-memory implementation for mem:// URLs
+//! - faulty: store wrapper that injects latency and failures into any provider
+//! - retry: store wrapper that retries transient failures with backoff
+//! - credentials: AWS credential provider chain shared by both S3 clients
+//! - journal: on-disk progress record that lets interrupted transfers resume
+//! - filter: include/exclude globs selecting the files of recursive transfers
+//! - plan: what a copy or sync would do, recorded instead of run on --dry-run
+//! - metadata: file attributes (mtime, mode, hash) stored with objects
+//! - compare: how syncs decide whether files present on both sides changed
+//! - sync: directory transfer and sync logic shared by all providers
+//! - bisync: two-way sync of a local directory and a remote prefix
+//! - watch: continuous local-to-remote sync driven by filesystem events
+//! - transfer: streaming uploads and ranged downloads shared by all providers
+//! - tls: insecure TLS connector for the AWS SDK
+//! - url: StorageUrl parser shared by all commands
+//!
+//! Every provider is backed by an object_store client. The trait provides
+//! default implementations of all operations on top of `store()`, so a
+//! provider only has to build its client; providers with richer native APIs
+//! (e.g. the AWS SDK) override individual operations.
+//!
+//! Usage:
+//! Commands in main.rs only talk to a `Box<dyn StorageBackend>`, so adding a
+//! provider means implementing the trait and extending build_backend.
+
+pub mod azure;
+pub mod bisync;
+pub mod compare;
+pub mod credentials;
+pub mod faulty;
+pub mod filter;
+pub mod gcs;
+pub mod journal;
+pub mod local;
+pub mod memory;
+pub mod metadata;
+pub mod plan;
+pub mod s3;
+mod sync;
+pub mod retry;
+pub mod tls;
+mod transfer;
+pub mod url;
+mod watch;
+
+use async_trait::async_trait;
+use azure::AzureStorage;
+use bisync::ConflictPolicy;
+use compare::Compare;
+use crate::config::{StorageConfig, StorageProvider, TransferOptions};
+use crate::error::{Result, ToolError};
+use futures::TryStreamExt;
+use faulty::{FaultInjector, FaultyStore};
+use filter::Filter;
+use futures_util::StreamExt;
+use gcs::GcsStorage;
+use journal::Journal;
+use local::LocalStorage;
+use memory::MemoryStorage;
+use metadata::FileMetadata;
+use retry::{RetryPolicy, RetryStore};
+use object_store::{ObjectStore, path::Path as ObjectPath};
+use plan::Plan;
+use s3::S3Storage;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, error};
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Provider name used in log messages (e.g. "S3", "GCS")
+    fn provider_name(&self) -> &'static str;
+
+    /// The object_store client the default implementations run on
+    fn store(&self) -> &Arc<dyn ObjectStore>;
+
+    /// Chunk size, concurrency and retry settings for transfers
+    fn transfer_options(&self) -> &TransferOptions;
+
+    /// State of the command the backend was built for
+    fn context(&self) -> &TransferContext;
+
+    /// Journal of the running copy or sync, if it is being recorded
+    fn journal(&self) -> Option<&Journal> {
+        self.context().journal.as_deref()
+    }
+
+    /// Include/exclude filter of the running copy or sync, if it has one
+    fn filter(&self) -> Option<&Filter> {
+        self.context().filter.as_deref()
+    }
+
+    /// Plan of the running copy or sync if it is a dry run; operations
+    /// record what they would do there instead of doing it
+    fn plan(&self) -> Option<&Plan> {
+        self.context().plan.as_deref()
+    }
+
+    /// Upload a single local file to `remote_path`, streaming large files
+    async fn upload_file(&self, local_path: &Path, remote_path: &str) -> Result<()> {
+        transfer::upload_file(self, local_path, remote_path).await
+    }
+
+    /// Download the object at `remote_path` into `local_path`, fetching
+    /// large objects as concurrent byte ranges
+    async fn download_file(&self, remote_path: &str, local_path: &Path) -> Result<()> {
+        transfer::download_file(self, remote_path, local_path).await
+    }
+
+    /// Copy `src_path` of `source` to `dest_path` of this backend. Copies
+    /// within one store run server-side; others stream through this process.
+    async fn copy_from(&self, source: &dyn StorageBackend, src_path: &str, dest_path: &str) -> Result<()> {
+        transfer::copy_from(self, source, src_path, dest_path).await
+    }
+
+    /// Copy `src_path` to `dest_path` within this backend
+    async fn copy_object(&self, src_path: &str, dest_path: &str) -> Result<()> {
+        transfer::copy_from(self, self, src_path, dest_path).await
+    }
+
+    /// Bucket, endpoint, region and identity other backends of the same provider can
+    /// copy from server-side, if the provider supports it
+    fn copy_source(&self) -> Option<CopySource> {
+        None
+    }
+
+    /// Attributes of the file an object was uploaded from, if the provider
+    /// stores them with the object
+    async fn file_metadata(&self, _path: &str) -> Result<Option<FileMetadata>> {
+        Ok(None)
+    }
+
+    /// Hex SHA-256 of an object's content, computed by reading the object
+    async fn content_sha256(&self, path: &str) -> Result<String> {
+        compare::sha256_object(self, path).await
+    }
+
+    /// List all object keys under `prefix`
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
+        info!("Listing objects in {} with prefix: {}", self.provider_name(), prefix);
+        let path = ObjectPath::from(prefix);
+        let mut objects = Vec::new();
+
+        let mut list_stream = self.store().list(Some(&path)).await
+            .map_err(|e| {
+                error!("Error listing objects in {}: {}", self.provider_name(), e);
+                ToolError::Storage(e)
+            })?;
+
+        while let Some(obj) = list_stream.next().await {
+            let obj = obj
+                .map_err(|e| {
+                    error!("Error listing objects in {}: {}", self.provider_name(), e);
+                    ToolError::Storage(e)
+                })?;
+            objects.push(obj.location.to_string());
+        }
+
+        info!("Successfully listed {} objects in {} with prefix: {}", objects.len(), self.provider_name(), prefix);
+        Ok(objects)
+    }
+
+    /// Size in bytes of a single object
+    async fn object_size(&self, path: &str) -> Result<u64> {
+        let path = ObjectPath::from(path);
+        let meta = self.store().head(&path).await
+            .map_err(|e| {
+                error!("Error getting metadata of {} in {}: {}", path, self.provider_name(), e);
+                ToolError::Storage(e)
+            })?;
+        Ok(meta.size as u64)
+    }
+
+    /// Delete a single object
+    async fn delete_object(&self, path: &str) -> Result<()> {
+        info!("Deleting object in {}: {}", self.provider_name(), path);
+        let path = ObjectPath::from(path);
+        self.store().delete(&path).await
+            .map_err(|e| {
+                error!("Error deleting object in {}: {}", self.provider_name(), e);
+                ToolError::Storage(e)
+            })?;
+        info!("Successfully deleted object in {}: {}", self.provider_name(), path);
+        Ok(())
+    }
+
+    /// Delete many objects, reporting each one that could not be deleted in
+    /// ToolError::Transfers. Providers with a multi-object delete API send
+    /// the keys in batches; the others delete them one by one, concurrently.
+    async fn delete_objects(&self, paths: &[String]) -> Result<()> {
+        let items = paths.iter().map(|path| (path.clone(), path.as_str())).collect();
+        sync::run_transfers(self.transfer_options().concurrent_uploads, items, |path| self.delete_object(path)).await
+    }
+
+    /// List objects under `prefix` with their size and modification time
+    async fn list_files_with_metadata(&self, prefix: &str) -> Result<HashMap<String, (u64, SystemTime)>> {
+        info!("Listing files with metadata in {} with prefix: {}", self.provider_name(), prefix);
+        let mut files = HashMap::new();
+
+        let prefix_path = ObjectPath::from(prefix);
+        let list_stream = self.store().list(Some(&prefix_path));
+
+        let mut stream = list_stream.await.map_err(|e| {
+            error!("Error listing objects in {}: {}", self.provider_name(), e);
+            ToolError::Storage(e)
+        })?;
+
+        while let Some(meta) = stream.try_next().await.map_err(|e| {
+            error!("Error listing objects in {}: {}", self.provider_name(), e);
+            ToolError::Storage(e)
+        })? {
+            let path = meta.location.to_string();
+            let size = meta.size as u64;
+            files.insert(path, (size, SystemTime::from(meta.last_modified)));
+        }
+
+        info!("Successfully listed {} files with metadata", files.len());
+        Ok(files)
+    }
+
+    /// Recursively upload a local directory under `remote_prefix`
+    async fn upload_directory(&self, local_dir: &Path, remote_prefix: &str) -> Result<()> {
+        sync::upload_directory(self, local_dir, remote_prefix).await
+    }
+
+    /// Recursively download everything under `remote_prefix` into `local_dir`
+    async fn download_directory(&self, remote_prefix: &str, local_dir: &Path) -> Result<()> {
+        sync::download_directory(self, remote_prefix, local_dir).await
+    }
+
+    /// Recursively copy everything under `source_prefix` of `source`, which
+    /// may be this backend or another one, to `dest_prefix` of this backend
+    async fn copy_directory_from(&self, source: &dyn StorageBackend, source_prefix: &str, dest_prefix: &str) -> Result<()> {
+        sync::copy_directory(source, source_prefix, self, dest_prefix).await
+    }
+
+    /// Delete every object under `prefix`
+    async fn remove_directory(&self, prefix: &str) -> Result<()> {
+        sync::remove_directory(self, prefix).await
+    }
+
+    /// Sync `source_prefix` of `source`, which may be this backend or one
+    /// for another bucket, account or provider, into `dest_prefix` of this
+    /// backend
+    async fn sync_directories_from(&self, source: &dyn StorageBackend, source_prefix: &str, dest_prefix: &str, options: &SyncOptions) -> Result<()> {
+        sync::sync_directories(source, source_prefix, self, dest_prefix, options).await
+    }
+
+    /// Sync a local directory into a remote prefix
+    async fn sync_local_to_remote(&self, local_dir: &Path, remote_prefix: &str, options: &SyncOptions) -> Result<()> {
+        sync::sync_local_to_remote(self, local_dir, remote_prefix, options).await
+    }
+
+    /// Sync a remote prefix into a local directory
+    async fn sync_remote_to_local(&self, remote_prefix: &str, local_dir: &Path, options: &SyncOptions) -> Result<()> {
+        sync::sync_remote_to_local(self, remote_prefix, local_dir, options).await
+    }
+
+    /// Sync a local directory into a remote prefix, then keep uploading files
+    /// that change once they have been left alone for `debounce`, until the
+    /// process is signalled
+    async fn watch_local_to_remote(&self, local_dir: &Path, remote_prefix: &str, options: &SyncOptions, debounce: Duration) -> Result<()> {
+        watch::watch_local_to_remote(self, local_dir, remote_prefix, options, debounce).await
+    }
+
+    /// Sync a local directory and a remote prefix in both directions,
+    /// against the state recorded in `baseline` by the previous run
+    async fn sync_bidirectional(&self, local_dir: &Path, remote_prefix: &str, baseline: &Path, policy: ConflictPolicy, options: &SyncOptions) -> Result<()> {
+        bisync::sync_bidirectional(self, local_dir, remote_prefix, baseline, policy, options).await
+    }
+}
+
+/// Per-command state shared by the backends a command builds; unlike
+/// TransferOptions it is not part of the configuration
+#[derive(Clone, Default)]
+pub struct TransferContext {
+    /// Journal of a copy or sync, recording its progress
+    pub journal: Option<Arc<Journal>>,
+    /// Include/exclude filter of a recursive copy, sync or remove
+    pub filter: Option<Arc<Filter>>,
+    /// Plan of a dry run
+    pub plan: Option<Arc<Plan>>,
+}
+
+/// A bucket that server-side copies can read from
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopySource {
+    pub provider: StorageProvider,
+    /// Custom endpoint of the service, None for the provider's default
+    pub endpoint: Option<String>,
+    /// Region the source's client signs for, for providers with regional
+    /// endpoints
+    pub region: Option<String>,
+    pub bucket: String,
+    /// Identity the source's client authenticates as (e.g. an access key
+    /// ID); the service only copies for a destination with the same one
+    pub identity: String,
+}
+
+/// What a sync transfers and removes
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Delete files in the destination that don't exist in the source
+    pub delete: bool,
+    /// How files present on both sides are compared
+    pub compare: Compare,
+    /// Abort before deleting more than this many files
+    pub max_delete: Option<usize>,
+    /// Abort before deleting more than this percentage of the destination's files
+    pub max_delete_percent: Option<u8>,
+    /// Where destination files are moved before they are deleted or
+    /// overwritten: a key prefix of the destination remote, or a directory
+    /// for local destinations
+    pub backup_dir: Option<String>,
+}
+
+/// Build the storage backend selected by `config.provider`
+pub async fn build_backend(config: &StorageConfig, transfer: &TransferOptions, context: &TransferContext) -> Result<Box<dyn StorageBackend>> {
+    match config.provider {
+        StorageProvider::S3 => Ok(Box::new(S3Storage::new(config, transfer, context).await?)),
+        StorageProvider::GCS => Ok(Box::new(GcsStorage::new(config, transfer, context)?)),
+        StorageProvider::Azure => Ok(Box::new(AzureStorage::new(config, transfer, context)?)),
+        StorageProvider::Local => Ok(Box::new(LocalStorage::new(config, transfer, context)?)),
+        StorageProvider::Memory => Ok(Box::new(MemoryStorage::new(config, transfer, context))),
+    }
+}
+
+/// Layer fault injection (if configured) and retries over a provider's
+/// client. Retries sit outside the faults so injected failures exercise them.
+fn wrap_store(store: Arc<dyn ObjectStore>, faults: Option<Arc<FaultInjector>>, transfer: &TransferOptions) -> Arc<dyn ObjectStore> {
+    let store = match faults {
+        Some(faults) => Arc::new(FaultyStore::new(store, faults)),
+        None => store,
+    };
+    Arc::new(RetryStore::new(store, RetryPolicy::from_options(transfer)))
+}
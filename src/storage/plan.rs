@@ -0,0 +1,316 @@
+//! Transfer Plan Module
+//!
+//! Collects what a copy or sync would do when it runs with `--dry-run`.
+//! The directory and sync operations make the same decisions as in a real
+//! run, from the same listings and comparisons, but record each of them
+//! here instead of transferring or deleting anything:
+//! - upload: the destination lacks the file
+//! - copy: the destination remote lacks the file, which would be copied
+//!   from another remote
+//! - download: the local destination lacks the file, which would be
+//!   downloaded from a remote
+//! - update: the destination's copy differs and would be replaced
+//! - delete: the file is not in the source (`sync --delete`)
+//! - skip: the file is unchanged or left out by a filter
+//!
+//! Every entry carries the reason for its action. The plan is printed as a
+//! table for review, or as JSON for scripts.
+
+use crate::error::{Result, ToolError};
+use serde::Serialize;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Upload,
+    Copy,
+    Download,
+    Update,
+    Delete,
+    Skip,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match self {
+            Action::Upload => "upload",
+            Action::Copy => "copy",
+            Action::Download => "download",
+            Action::Update => "update",
+            Action::Delete => "delete",
+            Action::Skip => "skip",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanEntry {
+    pub action: Action,
+    /// Destination path, or the source path of files left out by a filter
+    pub path: String,
+    /// Bytes the action transfers or removes, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    pub reason: String,
+}
+
+impl PlanEntry {
+    pub fn new(action: Action, path: impl Into<String>, size: Option<u64>, reason: impl Into<String>) -> Self {
+        Self {
+            action,
+            path: path.into(),
+            size,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// How a plan is printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum PlanFormat {
+    /// Aligned columns with a summary line
+    #[default]
+    Table,
+    /// A JSON array of entries
+    Json,
+}
+
+#[derive(Debug, Default)]
+pub struct Plan {
+    entries: Mutex<Vec<PlanEntry>>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, action: Action, path: impl Into<String>, size: Option<u64>, reason: impl Into<String>) {
+        self.entries.lock().unwrap().push(PlanEntry::new(action, path, size, reason));
+    }
+
+    pub fn extend(&self, entries: Vec<PlanEntry>) {
+        self.entries.lock().unwrap().extend(entries);
+    }
+
+    /// The entries ordered by action, then path
+    pub fn entries(&self) -> Vec<PlanEntry> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by(|a, b| (a.action, &a.path).cmp(&(b.action, &b.path)));
+        entries
+    }
+
+    /// Print the plan to stdout
+    pub fn print(&self, format: PlanFormat) -> Result<()> {
+        let entries = self.entries();
+        match format {
+            PlanFormat::Json => {
+                let json = serde_json::to_string_pretty(&entries)
+                    .map_err(|e| ToolError::InvalidOperation(format!("Cannot encode plan: {}", e)))?;
+                println!("{}", json);
+            }
+            PlanFormat::Table => {
+                let path_width = entries.iter().map(|entry| entry.path.len()).max().unwrap_or(0).max(4);
+                println!("{:<8}  {:>12}  {:<path_width$}  REASON", "ACTION", "SIZE", "PATH");
+                for entry in &entries {
+                    let size = entry.size.map(|size| size.to_string()).unwrap_or_default();
+                    println!("{:<8}  {:>12}  {:<path_width$}  {}", entry.action.name(), size, entry.path, entry.reason);
+                }
+
+                let count = |action| entries.iter().filter(|entry| entry.action == action).count();
+                let bytes: u64 = entries.iter()
+                    .filter(|entry| matches!(entry.action, Action::Upload | Action::Copy | Action::Download | Action::Update))
+                    .filter_map(|entry| entry.size)
+                    .sum();
+                println!(
+                    "\n{} to upload, {} to copy, {} to download and {} to update ({} bytes), {} to delete, {} skipped",
+                    count(Action::Upload),
+                    count(Action::Copy),
+                    count(Action::Download),
+                    count(Action::Update),
+                    bytes,
+                    count(Action::Delete),
+                    count(Action::Skip)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StorageConfig};
+    use crate::storage::compare::Compare;
+    use crate::storage::filter::Filter;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::{StorageBackend, SyncOptions, TransferContext};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// A remote whose copies and syncs only plan, leaving out `*.tmp` files
+    fn dry_run() -> (MemoryStorage, Arc<Plan>) {
+        let plan = Arc::new(Plan::new());
+        let context = TransferContext {
+            filter: Filter::new(&[], &["*.tmp".to_string()], None).unwrap().map(Arc::new),
+            plan: Some(plan.clone()),
+            ..Default::default()
+        };
+        (MemoryStorage::new(&StorageConfig::default(), &Config::default().transfer_options, &context), plan)
+    }
+
+    fn memory() -> MemoryStorage {
+        MemoryStorage::new(&StorageConfig::default(), &Config::default().transfer_options, &TransferContext::default())
+    }
+
+    fn write(dir: &Path, path: &str, content: &[u8]) {
+        std::fs::write(dir.join(path), content).unwrap();
+    }
+
+    /// Upload `content` to `key`; uploads of single files are not planned
+    async fn put(remote: &dyn StorageBackend, key: &str, content: &[u8]) {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), content).unwrap();
+        remote.upload_file(file.path(), key).await.unwrap();
+    }
+
+    fn entries(plan: &Plan) -> Vec<(Action, String, Option<u64>, String)> {
+        plan.entries().into_iter().map(|entry| (entry.action, entry.path, entry.size, entry.reason)).collect()
+    }
+
+    fn entry(action: Action, path: impl Into<String>, size: u64, reason: &str) -> (Action, String, Option<u64>, String) {
+        (action, path.into(), Some(size), reason.to_string())
+    }
+
+    fn local(dir: &Path, path: &str) -> String {
+        dir.join(path).display().to_string()
+    }
+
+    async fn keys(remote: &dyn StorageBackend, prefix: &str) -> Vec<(String, u64)> {
+        let mut keys: Vec<_> = remote.list_files_with_metadata(prefix).await.unwrap()
+            .into_iter()
+            .map(|(key, (size, _))| (key, size))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn files(dir: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut files: Vec<_> = std::fs::read_dir(dir).unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name().to_string_lossy().to_string(), std::fs::read(entry.path()).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn copies_overwrite_every_selected_file() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "a.txt", b"aaa");
+        write(dir.path(), "scratch.tmp", b"t");
+        let (remote, plan) = dry_run();
+        remote.upload_directory(dir.path(), "up").await.unwrap();
+        assert_eq!(entries(&plan), [
+            entry(Action::Upload, "up/a.txt", 3, "copy overwrites without comparing"),
+            (Action::Skip, local(dir.path(), "scratch.tmp"), None, "excluded by filter".to_string()),
+        ]);
+        assert!(keys(&remote, "").await.is_empty());
+
+        let (remote, plan) = dry_run();
+        put(&remote, "runs/b.bin", b"bbbb").await;
+        put(&remote, "runs/c.tmp", b"cc").await;
+        let empty = tempfile::tempdir().unwrap();
+        remote.download_directory("runs", empty.path()).await.unwrap();
+        let source = memory();
+        put(&source, "src/d.bin", b"ddddd").await;
+        remote.copy_directory_from(&source, "src", "copied").await.unwrap();
+        assert_eq!(entries(&plan), [
+            entry(Action::Copy, "copied/d.bin", 5, "copy overwrites without comparing"),
+            entry(Action::Download, local(empty.path(), "b.bin"), 4, "copy overwrites without comparing"),
+            entry(Action::Skip, "runs/c.tmp", 2, "excluded by filter"),
+        ]);
+        assert!(files(empty.path()).is_empty());
+        assert_eq!(keys(&remote, "").await, [("runs/b.bin".to_string(), 4), ("runs/c.tmp".to_string(), 2)]);
+    }
+
+    #[tokio::test]
+    async fn sync_to_a_remote_plans_each_decision_with_its_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "new.txt", b"new");
+        write(dir.path(), "same.txt", b"same");
+        write(dir.path(), "edited.txt", b"edit");
+        write(dir.path(), "grown.txt", b"grown");
+        write(dir.path(), "scratch.tmp", b"t");
+        let (remote, plan) = dry_run();
+        put(&remote, "runs/same.txt", b"same").await;
+        put(&remote, "runs/edited.txt", b"EDIT").await;
+        put(&remote, "runs/grown.txt", b"gro").await;
+        put(&remote, "runs/old.txt", b"old!").await;
+        let before = keys(&remote, "runs").await;
+
+        let options = SyncOptions { delete: true, compare: Compare::Checksum, ..Default::default() };
+        remote.sync_local_to_remote(dir.path(), "runs", &options).await.unwrap();
+        assert_eq!(entries(&plan), [
+            entry(Action::Upload, "runs/new.txt", 3, "not in destination"),
+            entry(Action::Update, "runs/edited.txt", 4, "checksum differs"),
+            entry(Action::Update, "runs/grown.txt", 5, "size differs (3 -> 5 bytes)"),
+            entry(Action::Delete, "runs/old.txt", 4, "not in source"),
+            (Action::Skip, local(dir.path(), "scratch.tmp"), None, "excluded by filter".to_string()),
+            entry(Action::Skip, "runs/same.txt", 4, "unchanged (checksum)"),
+        ]);
+        assert_eq!(keys(&remote, "runs").await, before);
+    }
+
+    #[tokio::test]
+    async fn sync_to_a_local_directory_plans_each_decision_with_its_reason() {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "same.txt", b"same");
+        write(dir.path(), "edited.txt", b"edit");
+        write(dir.path(), "grown.txt", b"gro");
+        write(dir.path(), "old.txt", b"old!");
+        let (remote, plan) = dry_run();
+        put(&remote, "runs/new.txt", b"new").await;
+        put(&remote, "runs/same.txt", b"same").await;
+        put(&remote, "runs/edited.txt", b"EDIT").await;
+        put(&remote, "runs/grown.txt", b"grown").await;
+        put(&remote, "runs/scratch.tmp", b"t").await;
+        let before = files(dir.path());
+
+        let options = SyncOptions { delete: true, compare: Compare::Checksum, ..Default::default() };
+        remote.sync_remote_to_local("runs", dir.path(), &options).await.unwrap();
+        assert_eq!(entries(&plan), [
+            entry(Action::Download, local(dir.path(), "new.txt"), 3, "not in destination"),
+            entry(Action::Update, local(dir.path(), "edited.txt"), 4, "checksum differs"),
+            entry(Action::Update, local(dir.path(), "grown.txt"), 5, "size differs (3 -> 5 bytes)"),
+            entry(Action::Delete, local(dir.path(), "old.txt"), 4, "not in source"),
+            entry(Action::Skip, local(dir.path(), "same.txt"), 4, "unchanged (checksum)"),
+            entry(Action::Skip, "runs/scratch.tmp", 1, "excluded by filter"),
+        ]);
+        assert_eq!(files(dir.path()), before);
+    }
+
+    #[tokio::test]
+    async fn sync_between_remotes_plans_copies() {
+        let source = memory();
+        put(&source, "src/new.txt", b"new").await;
+        put(&source, "src/grown.txt", b"grown").await;
+        let (remote, plan) = dry_run();
+        put(&remote, "runs/grown.txt", b"gro").await;
+        put(&remote, "runs/old.txt", b"old!").await;
+        let before = keys(&remote, "runs").await;
+
+        let options = SyncOptions { delete: true, ..Default::default() };
+        remote.sync_directories_from(&source, "src", "runs", &options).await.unwrap();
+        assert_eq!(entries(&plan), [
+            entry(Action::Copy, "runs/new.txt", 3, "not in destination"),
+            entry(Action::Update, "runs/grown.txt", 5, "size differs (3 -> 5 bytes)"),
+            entry(Action::Delete, "runs/old.txt", 4, "not in source"),
+        ]);
+        assert_eq!(keys(&remote, "runs").await, before);
+    }
+}
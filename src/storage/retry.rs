@@ -0,0 +1,566 @@
+//! Retry Module
+//!
+//! Retries failed storage calls with exponential backoff and jitter, as
+//! configured by `transfer_options`:
+//! - retry_attempts: retries after the first attempt (0 disables retrying)
+//! - retry_base_delay_ms / retry_max_delay_ms: backoff starts at the base
+//!   delay, doubles per retry and is capped at the maximum; each delay is
+//!   jittered between half and all of its value
+//! - request_timeout_secs / transfer_timeout_secs: per-attempt timeouts for
+//!   metadata requests and data transfers (0 disables)
+//!
+//! Errors are classified before retrying. Throttling (429, SlowDown), server
+//! errors (5xx), timeouts, dropped connections and truncated bodies are
+//! retried; everything else (not found, permission denied, invalid input) is
+//! returned immediately. Once retries are exhausted the error is wrapped in
+//! RetriesExhausted, which is never retried again by an outer retry loop.
+//!
+//! RetryStore applies the policy to every object_store call of a backend;
+//! `retry` is used directly for AWS SDK calls and whole-file operations.
+//! object_store clients also retry server errors of each HTTP request they
+//! send with the same budget (see `store_retry_config`), which covers list
+//! pages and multipart writer parts; errors they already retried are not
+//! retried again by RetryStore.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use object_store::path::Path as ObjectPath;
+use object_store::{BackoffConfig, GetResult, ListResult, MultipartId, ObjectMeta, ObjectStore, RetryConfig};
+use crate::config::TransferOptions;
+use crate::error::ToolError;
+use rand::Rng;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tracing::warn;
+
+/// Lower-cased message fragments that mark an error as transient
+const TRANSIENT_MARKERS: [&str; 16] = [
+    "429 too many requests",
+    "408 request timeout",
+    "500 internal server error",
+    "502 bad gateway",
+    "503 service unavailable",
+    "504 gateway timeout",
+    "slowdown",
+    "throttl",
+    "timed out",
+    "timeout",
+    "connection reset",
+    "connection closed",
+    "broken pipe",
+    "error sending request",
+    "truncated",
+    "unexpected eof",
+];
+
+/// How long a single attempt of an operation may take
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperationKind {
+    /// Metadata request: head, list, delete, multipart bookkeeping
+    Request,
+    /// Request that moves object data: put, ranged get, part upload, copy
+    Transfer,
+    /// Whole-file operation made of several requests with their own timeouts
+    File,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub request_timeout: Option<Duration>,
+    pub transfer_timeout: Option<Duration>,
+}
+
+impl RetryPolicy {
+    pub fn from_options(options: &TransferOptions) -> Self {
+        let timeout = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        Self {
+            retries: options.retry_attempts,
+            base_delay: Duration::from_millis(options.retry_base_delay_ms),
+            max_delay: Duration::from_millis(options.retry_max_delay_ms),
+            request_timeout: timeout(options.request_timeout_secs),
+            transfer_timeout: timeout(options.transfer_timeout_secs),
+        }
+    }
+
+    fn timeout(&self, kind: OperationKind) -> Option<Duration> {
+        match kind {
+            OperationKind::Request => self.request_timeout,
+            OperationKind::Transfer => self.transfer_timeout,
+            OperationKind::File => None,
+        }
+    }
+
+    /// Delay before retry number `retry` (starting at 1)
+    fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self.base_delay.saturating_mul(2u32.saturating_pow(retry - 1));
+        let capped = exponential.min(self.max_delay);
+        let half = capped / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// Errors the retry loop knows how to classify and annotate
+pub trait Retryable: Sized + fmt::Display {
+    fn is_retryable(&self) -> bool;
+
+    /// Error for an attempt that exceeded its timeout
+    fn timed_out(operation: &str, after: Duration) -> Self;
+
+    /// Mark a retryable error as final after `attempts` attempts
+    fn exhausted(self, operation: &str, attempts: u32) -> Self;
+}
+
+/// A transient error that kept failing until the retry budget ran out
+#[derive(Debug)]
+pub struct RetriesExhausted {
+    operation: String,
+    attempts: u32,
+    source: Box<dyn Error + Send + Sync>,
+}
+
+impl fmt::Display for RetriesExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed after {} attempts: {}", self.operation, self.attempts, self.source)
+    }
+}
+
+impl Error for RetriesExhausted {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+/// An error whose origin already determined whether it is transient
+/// (e.g. from AWS SDK error codes)
+#[derive(Debug)]
+pub struct Classified {
+    message: String,
+    retryable: bool,
+}
+
+impl Classified {
+    pub fn new(message: impl Into<String>, retryable: bool) -> Self {
+        Self { message: message.into(), retryable }
+    }
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for Classified {}
+
+#[derive(Debug)]
+struct TimedOut {
+    operation: String,
+    after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} timed out after {:?}", self.operation, self.after)
+    }
+}
+
+impl Error for TimedOut {}
+
+impl Retryable for object_store::Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            object_store::Error::Generic { source, .. } => {
+                !source.is::<RetriesExhausted>() && is_transient(source.as_ref())
+            }
+            // NotFound, AlreadyExists, Precondition, InvalidPath, NotSupported, ...
+            _ => false,
+        }
+    }
+
+    fn timed_out(operation: &str, after: Duration) -> Self {
+        object_store::Error::Generic {
+            store: "Retry",
+            source: Box::new(TimedOut { operation: operation.to_string(), after }),
+        }
+    }
+
+    fn exhausted(self, operation: &str, attempts: u32) -> Self {
+        object_store::Error::Generic {
+            store: "Retry",
+            source: Box::new(RetriesExhausted { operation: operation.to_string(), attempts, source: Box::new(self) }),
+        }
+    }
+}
+
+impl Retryable for ToolError {
+    fn is_retryable(&self) -> bool {
+        match self {
+            ToolError::Storage(e) => e.is_retryable(),
+            ToolError::Io(e) => is_transient(e),
+            _ => false,
+        }
+    }
+
+    fn timed_out(operation: &str, after: Duration) -> Self {
+        ToolError::Storage(object_store::Error::timed_out(operation, after))
+    }
+
+    fn exhausted(self, operation: &str, attempts: u32) -> Self {
+        match self {
+            ToolError::Storage(e) => ToolError::Storage(e.exhausted(operation, attempts)),
+            other => ToolError::Storage(object_store::Error::Generic {
+                store: "Retry",
+                source: Box::new(RetriesExhausted { operation: operation.to_string(), attempts, source: Box::new(other) }),
+            }),
+        }
+    }
+}
+
+/// Walk an error chain looking for signs of a transient failure
+fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if let Some(classified) = error.downcast_ref::<Classified>() {
+            return classified.retryable;
+        }
+        if error.is::<TimedOut>() {
+            return true;
+        }
+        if let Some(io) = error.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::TimedOut
+                    | ErrorKind::UnexpectedEof
+                    | ErrorKind::Interrupted
+            ) {
+                return true;
+            }
+        }
+        let message = error.to_string().to_lowercase();
+        if retried_by_store(&message) {
+            return false;
+        }
+        if TRANSIENT_MARKERS.iter().any(|marker| message.contains(marker)) {
+            return true;
+        }
+        current = error.source();
+    }
+    false
+}
+
+/// Whether object_store already retried the request that failed, reported
+/// by it as `response error "...", after N retries`; retrying it again
+/// would multiply the retry budget
+fn retried_by_store(message: &str) -> bool {
+    message.split(", after ").skip(1).any(|rest| {
+        rest.split_once(" retries")
+            .and_then(|(count, _)| count.parse::<u32>().ok())
+            .is_some_and(|count| count > 0)
+    })
+}
+
+/// Run `attempt` until it succeeds, fails with a fatal error or runs out of retries
+pub async fn retry<T, E, F, Fut>(policy: &RetryPolicy, kind: OperationKind, operation: &str, mut attempt: F) -> Result<T, E>
+where
+    E: Retryable,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut retries = 0;
+    loop {
+        let result = match policy.timeout(kind) {
+            Some(timeout) => tokio::time::timeout(timeout, attempt()).await
+                .unwrap_or_else(|_| Err(E::timed_out(operation, timeout))),
+            None => attempt().await,
+        };
+
+        match result {
+            Ok(value) => return Ok(value),
+            Err(e) if !e.is_retryable() => return Err(e),
+            Err(e) if retries >= policy.retries => return Err(e.exhausted(operation, retries + 1)),
+            Err(e) => {
+                retries += 1;
+                let delay = policy.backoff(retries);
+                warn!(
+                    "{} failed (retry {} of {} in {:?}): {}",
+                    operation, retries, policy.retries, delay, e
+                );
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+/// object_store's own retries, with the policy's budget and backoff. They
+/// cover every HTTP request it sends, including the pages of a listing and
+/// the parts of a multipart writer, which RetryStore cannot reach; they only
+/// retry server errors.
+pub fn store_retry_config(policy: &RetryPolicy) -> RetryConfig {
+    RetryConfig {
+        backoff: BackoffConfig {
+            init_backoff: policy.base_delay,
+            max_backoff: policy.max_delay,
+            base: 2.0,
+        },
+        max_retries: policy.retries as usize,
+        ..Default::default()
+    }
+}
+
+/// ObjectStore wrapper that retries every call according to a RetryPolicy
+#[derive(Debug)]
+pub struct RetryStore {
+    inner: Arc<dyn ObjectStore>,
+    policy: RetryPolicy,
+}
+
+impl RetryStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl fmt::Display for RetryStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RetryStore({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for RetryStore {
+    async fn put(&self, location: &ObjectPath, bytes: Bytes) -> object_store::Result<()> {
+        retry(&self.policy, OperationKind::Transfer, &format!("put {}", location), || {
+            self.inner.put(location, bytes.clone())
+        }).await
+    }
+
+    async fn put_multipart(
+        &self,
+        location: &ObjectPath,
+    ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+        retry(&self.policy, OperationKind::Request, &format!("put_multipart {}", location), || {
+            self.inner.put_multipart(location)
+        }).await
+    }
+
+    async fn abort_multipart(&self, location: &ObjectPath, multipart_id: &MultipartId) -> object_store::Result<()> {
+        retry(&self.policy, OperationKind::Request, &format!("abort_multipart {}", location), || {
+            self.inner.abort_multipart(location, multipart_id)
+        }).await
+    }
+
+    async fn get(&self, location: &ObjectPath) -> object_store::Result<GetResult> {
+        retry(&self.policy, OperationKind::Request, &format!("get {}", location), || {
+            self.inner.get(location)
+        }).await
+    }
+
+    async fn get_range(&self, location: &ObjectPath, range: Range<usize>) -> object_store::Result<Bytes> {
+        retry(&self.policy, OperationKind::Transfer, &format!("get_range {} {:?}", location, range), || {
+            self.inner.get_range(location, range.clone())
+        }).await
+    }
+
+    async fn head(&self, location: &ObjectPath) -> object_store::Result<ObjectMeta> {
+        retry(&self.policy, OperationKind::Request, &format!("head {}", location), || {
+            self.inner.head(location)
+        }).await
+    }
+
+    async fn delete(&self, location: &ObjectPath) -> object_store::Result<()> {
+        retry(&self.policy, OperationKind::Request, &format!("delete {}", location), || {
+            self.inner.delete(location)
+        }).await
+    }
+
+    async fn list(
+        &self,
+        prefix: Option<&ObjectPath>,
+    ) -> object_store::Result<BoxStream<'_, object_store::Result<ObjectMeta>>> {
+        retry(&self.policy, OperationKind::Request, "list", || {
+            self.inner.list(prefix)
+        }).await
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&ObjectPath>) -> object_store::Result<ListResult> {
+        retry(&self.policy, OperationKind::Request, "list_with_delimiter", || {
+            self.inner.list_with_delimiter(prefix)
+        }).await
+    }
+
+    async fn copy(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        retry(&self.policy, OperationKind::Transfer, &format!("copy {} to {}", from, to), || {
+            self.inner.copy(from, to)
+        }).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &ObjectPath, to: &ObjectPath) -> object_store::Result<()> {
+        retry(&self.policy, OperationKind::Transfer, &format!("copy_if_not_exists {} to {}", from, to), || {
+            self.inner.copy_if_not_exists(from, to)
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[derive(Debug)]
+    struct Message(&'static str);
+
+    impl fmt::Display for Message {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl Error for Message {}
+
+    fn generic(message: &'static str) -> object_store::Error {
+        object_store::Error::Generic { store: "S3", source: Box::new(Message(message)) }
+    }
+
+    fn policy(retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            retries,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(2),
+            request_timeout: Some(Duration::from_millis(20)),
+            transfer_timeout: None,
+        }
+    }
+
+    #[test]
+    fn server_errors_and_throttling_are_retried() {
+        assert!(generic("Server returned 503 Service Unavailable").is_retryable());
+        assert!(generic("502 Bad Gateway").is_retryable());
+        assert!(generic("503 SlowDown: Please reduce your request rate").is_retryable());
+        assert!(generic("429 Too Many Requests").is_retryable());
+    }
+
+    #[test]
+    fn timeouts_and_broken_connections_are_retried() {
+        assert!(object_store::Error::timed_out("head x", Duration::from_secs(30)).is_retryable());
+        assert!(generic("operation timed out").is_retryable());
+        assert!(generic("connection reset by peer").is_retryable());
+        assert!(generic("response body truncated (injected)").is_retryable());
+        assert!(ToolError::Io(std::io::Error::new(ErrorKind::UnexpectedEof, "early eof")).is_retryable());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        assert!(!generic("403 Forbidden: Access Denied").is_retryable());
+        assert!(!generic("400 Bad Request").is_retryable());
+        let not_found = object_store::Error::NotFound { path: "x".into(), source: Box::new(Message("404")) };
+        assert!(!not_found.is_retryable());
+        assert!(!ToolError::Io(std::io::Error::new(ErrorKind::PermissionDenied, "denied")).is_retryable());
+        assert!(!ToolError::InvalidOperation("bad input".into()).is_retryable());
+    }
+
+    #[test]
+    fn classified_errors_keep_their_classification() {
+        let error = |retryable| object_store::Error::Generic { store: "S3", source: Box::new(Classified::new("503 Service Unavailable", retryable)) };
+        assert!(error(true).is_retryable());
+        assert!(!error(false).is_retryable());
+    }
+
+    #[test]
+    fn errors_object_store_already_retried_are_not_retried_again() {
+        assert!(retried_by_store(r#"response error "503 service unavailable", after 3 retries: server error"#));
+        assert!(!retried_by_store(r#"response error "request error", after 0 retries: timed out"#));
+        assert!(!retried_by_store("connection reset"));
+        assert!(!generic(r#"Error performing list request: response error "503 Service Unavailable", after 3 retries"#).is_retryable());
+        assert!(generic(r#"Error performing list request: response error "request error", after 0 retries: error sending request"#).is_retryable());
+        assert!(!generic("x").exhausted("get x", 4).is_retryable());
+        assert!(!generic("503 Service Unavailable").exhausted("get x", 4).is_retryable());
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_cap() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            ..policy(10)
+        };
+        for (retry, expected) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (6, 1000), (40, 1000)] {
+            for _ in 0..20 {
+                let delay = policy.backoff(retry);
+                let expected = Duration::from_millis(expected);
+                assert!(delay >= expected / 2 && delay <= expected, "retry {}: {:?} outside {:?}", retry, delay, expected);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_retry_budget() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = retry(&policy(3), OperationKind::Request, "get x", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(generic("503 Service Unavailable"))
+        }).await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+        match result {
+            Err(object_store::Error::Generic { source, .. }) => {
+                let exhausted = source.downcast_ref::<RetriesExhausted>().unwrap();
+                assert_eq!(exhausted.attempts, 4);
+                assert_eq!(exhausted.operation, "get x");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn returns_permanent_errors_immediately() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), _> = retry(&policy(3), OperationKind::Request, "get x", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(generic("403 Forbidden"))
+        }).await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert!(matches!(result, Err(object_store::Error::Generic { store: "S3", .. })));
+    }
+
+    #[tokio::test]
+    async fn succeeds_once_a_transient_error_clears() {
+        let attempts = AtomicU32::new(0);
+        let result = retry(&policy(3), OperationKind::Request, "get x", || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(generic("connection reset")),
+                _ => Ok("data"),
+            }
+        }).await;
+
+        assert_eq!(result.unwrap(), "data");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn attempts_that_exceed_their_timeout_are_retried() {
+        let attempts = AtomicU32::new(0);
+        let result: Result<(), object_store::Error> = retry(&policy(1), OperationKind::Request, "head x", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        }).await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(result.unwrap_err().to_string().contains("timed out after"));
+    }
+}
@@ -0,0 +1,1073 @@
+//! Directory Transfer and Sync Module
+//!
+//! Provider-independent implementations of the directory and sync operations
+//! of `StorageBackend`. Each function is written against the trait's primitive
+//! operations (upload_file, download_file, list_files_with_metadata, ...), so
+//! every backend gets the same transfer and comparison semantics.
+//!
+//! Operations:
+//! - upload_directory / download_directory / copy_directory: recursive copies
+//! - sync_directories: remote prefix to remote prefix, on the same or
+//!   another backend; objects go through the destination's copy_from, so
+//!   they are copied server-side where possible and streamed otherwise
+//! - sync_local_to_remote / sync_remote_to_local: local <-> remote
+//! - remove_directory: deletes every object under a prefix
+//!
+//! Files of different sizes are always transferred; files of the same size
+//! are compared as `options.compare` selects (see the compare module), which
+//! may take a request or a hash per file, so those comparisons run
+//! concurrently. `options.delete` removes files from the destination that no
+//! longer exist in the source.
+//!
+//! Concurrency:
+//! File transfers run through a bounded pipeline with at most
+//! `transfer_options.concurrent_uploads` files in flight; each file's parts
+//! or ranges are limited separately by `concurrent_parts`. Remote deletes go
+//! through delete_objects, batched where the provider supports it. A failing
+//! file does not stop the others: every failure is collected and reported
+//! together as ToolError::Transfers.
+//!
+//! Filtering:
+//! When the command has include/exclude filters, local walks, remote
+//! listings and therefore the delete phase only see the files the filter
+//! selects; excluded directories are not descended into.
+//!
+//! Safeguards:
+//! Deletes are decided before anything is transferred. A sync refuses to
+//! delete when the source listed no files, or more files than
+//! `options.max_delete`/`max_delete_percent` allow. With
+//! `options.backup_dir`, files about to be overwritten are copied, and files
+//! about to be deleted moved, to the same relative path under it.
+//!
+//! Dry runs:
+//! When the command has a plan, the functions record their decisions in it
+//! and return before transferring or deleting anything.
+//!
+//! Resuming:
+//! When the command records a journal, files it lists as transferred from an
+//! unchanged source are skipped, and every file is recorded as it completes.
+
+use super::compare;
+use super::filter::Filter;
+use super::journal::{Fingerprint, Journal};
+use super::plan::{Action, Plan, PlanEntry};
+use super::transfer::PARTIAL_SUFFIX;
+use super::{StorageBackend, SyncOptions};
+use crate::error::{Result, ToolError};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::SystemTime;
+use tracing::{info, error, warn};
+
+/// Reasons recorded in a dry run's plan
+const MISSING: &str = "not in destination";
+const NOT_IN_SOURCE: &str = "not in source";
+const EXCLUDED: &str = "excluded by filter";
+const COPY: &str = "copy overwrites without comparing";
+
+/// Walk a local directory, returning every file the filter selects with its
+/// path relative to `path`. A dry run's plan notes what the filter leaves out.
+/// Partial files of downloads in progress are not part of the directory: a
+/// resumed download renames them into place.
+pub(crate) async fn list_files_recursively(path: &std::path::Path, filter: Option<&Filter>, plan: Option<&Plan>) -> Result<Vec<(std::path::PathBuf, std::path::PathBuf)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![path.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await
+            .map_err(|e| {
+                error!("Error reading directory {}: {}", dir.display(), e);
+                ToolError::Io(e)
+            })?;
+
+        while let Some(entry) = entries.next_entry().await
+            .map_err(|e| {
+                error!("Error reading directory entry: {}", e);
+                ToolError::Io(e)
+            })? {
+            let file_type = entry.file_type().await
+                .map_err(|e| {
+                    error!("Error getting file type: {}", e);
+                    ToolError::Io(e)
+                })?;
+
+            let relative_path = entry.path().strip_prefix(path)
+                .map_err(|e| {
+                    error!("Error computing relative path: {}", e);
+                    ToolError::Config(e.to_string())
+                })?
+                .to_path_buf();
+            let relative = relative_path.to_string_lossy();
+
+            if file_type.is_dir() {
+                if filter.is_some_and(|filter| filter.excludes_dir(&relative)) {
+                    if let Some(plan) = plan {
+                        plan.add(Action::Skip, format!("{}/", entry.path().display()), None, EXCLUDED);
+                    }
+                    continue;
+                }
+                dirs.push(entry.path());
+            } else if relative.ends_with(PARTIAL_SUFFIX) {
+                continue;
+            } else if filter.is_none_or(|filter| filter.matches(&relative)) {
+                files.push((entry.path(), relative_path));
+            } else if let Some(plan) = plan {
+                plan.add(Action::Skip, entry.path().display().to_string(), None, EXCLUDED);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+/// Path of `key` relative to `prefix`
+pub(super) fn relative_key<'a>(key: &'a str, prefix: &str) -> &'a str {
+    key.strip_prefix(prefix).unwrap_or(key).trim_start_matches('/')
+}
+
+/// List objects under `prefix` with their size and modification time,
+/// leaving out those the filter does not select. A dry run's plan notes
+/// what the filter leaves out.
+pub(super) async fn list_filtered<B: StorageBackend + ?Sized>(backend: &B, prefix: &str, filter: Option<&Filter>, plan: Option<&Plan>) -> Result<HashMap<String, (u64, SystemTime)>> {
+    let mut files = backend.list_files_with_metadata(prefix).await?;
+    if let Some(filter) = filter {
+        let total = files.len();
+        files.retain(|key, (size, _)| {
+            let selected = filter.matches(relative_key(key, prefix));
+            if let (false, Some(plan)) = (selected, plan) {
+                plan.add(Action::Skip, key.clone(), Some(*size), EXCLUDED);
+            }
+            selected
+        });
+        if files.len() < total {
+            info!("Filters leave out {} of {} objects under {}", total - files.len(), total, prefix);
+        }
+    }
+    Ok(files)
+}
+
+/// Record every item in a dry run's plan as `action`; copies overwrite the
+/// destination without comparing
+fn plan_copies<T>(plan: &Plan, action: Action, items: &[(String, Fingerprint, T)]) {
+    for (path, source, _) in items {
+        plan.add(action, path.clone(), Some(source.size), COPY);
+    }
+}
+
+fn size_differs(source_size: u64, dest_size: u64) -> String {
+    format!("size differs ({} -> {} bytes)", dest_size, source_size)
+}
+
+/// Plan entry for a file of the same size on both sides, compared as
+/// `options.compare` selects
+fn compared(path: &str, size: u64, current: bool, options: &SyncOptions) -> PlanEntry {
+    let compare = options.compare.name();
+    if current {
+        PlanEntry::new(Action::Skip, path, Some(size), format!("unchanged ({})", compare))
+    } else {
+        PlanEntry::new(Action::Update, path, Some(size), format!("{} differs", compare))
+    }
+}
+
+/// Refuse the deletions a sync planned if the source listed no files or
+/// there are more than `--max-delete`/`--max-delete-percent` allow. A dry run
+/// only warns, so its plan shows what would have been deleted.
+pub(super) fn check_deletes(plan: Option<&Plan>, options: &SyncOptions, source: &str, source_count: usize, dest_count: usize, deletes: usize) -> Result<()> {
+    if deletes == 0 {
+        return Ok(());
+    }
+    let refusal = if source_count == 0 {
+        format!("the source {} listed no files", source)
+    } else if let Some(max) = options.max_delete.filter(|max| deletes > *max) {
+        format!("--max-delete is {}", max)
+    } else if let Some(percent) = options.max_delete_percent.filter(|percent| deletes * 100 > *percent as usize * dest_count) {
+        format!("more than {}% of the {} files in the destination", percent, dest_count)
+    } else {
+        return Ok(());
+    };
+
+    let message = format!("Refusing to delete {} files: {}", deletes, refusal);
+    if plan.is_some() {
+        warn!("{}", message);
+        return Ok(());
+    }
+    error!("{}", message);
+    Err(ToolError::InvalidOperation(message))
+}
+
+/// The key prefix `--backup-dir` names in the destination remote. It must
+/// lie outside the synced prefix, or the next sync would delete the backups.
+pub(super) fn remote_backup_dir<'a>(options: &'a SyncOptions, dest_prefix: &str) -> Result<Option<&'a str>> {
+    let Some(backup_dir) = options.backup_dir.as_deref() else {
+        return Ok(None);
+    };
+    let backup_dir = backup_dir.trim_matches('/');
+    let dest = dest_prefix.trim_matches('/');
+    if backup_dir.is_empty() || dest.is_empty() || backup_dir == dest || backup_dir.starts_with(&format!("{}/", dest)) {
+        return Err(ToolError::Config(format!("--backup-dir {} must lie outside the destination {}", backup_dir, dest_prefix)));
+    }
+    Ok(Some(backup_dir))
+}
+
+/// The directory `--backup-dir` names for a local destination, which must
+/// lie outside it
+fn local_backup_dir(options: &SyncOptions, local_dir: &std::path::Path) -> Result<Option<std::path::PathBuf>> {
+    let Some(backup_dir) = options.backup_dir.as_deref() else {
+        return Ok(None);
+    };
+    let backup_dir = std::path::absolute(backup_dir)?;
+    if backup_dir.starts_with(std::path::absolute(local_dir)?) {
+        return Err(ToolError::Config(format!("--backup-dir {} must lie outside the destination {}", backup_dir.display(), local_dir.display())));
+    }
+    Ok(Some(backup_dir))
+}
+
+/// Copy the destination object at `path` to the same relative key under
+/// `backup_dir`, removing the original if the sync deletes it
+pub(super) async fn back_up_object<B: StorageBackend + ?Sized>(backend: &B, backup_dir: &str, prefix: &str, path: &str, remove: bool) -> Result<()> {
+    let backup = format!("{}/{}", backup_dir, relative_key(path, prefix));
+    info!("Backing up {} to {}", path, backup);
+    backend.copy_object(path, &backup).await?;
+    if remove {
+        backend.delete_object(path).await?;
+    }
+    Ok(())
+}
+
+/// Delete destination objects in batches, or move them under the backup
+/// prefix one by one if there is one
+pub(super) async fn delete_remote<B: StorageBackend + ?Sized>(backend: &B, backup_dir: Option<&str>, prefix: &str, paths: Vec<String>) -> Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    match backup_dir {
+        Some(backup_dir) => {
+            let items = paths.into_iter().map(|path| (path.clone(), path)).collect();
+            run_transfers(backend.transfer_options().concurrent_uploads, items, |path| async move {
+                back_up_object(backend, backup_dir, prefix, &path, true).await
+            }).await
+        }
+        None => {
+            info!("Deleting {} objects from {}", paths.len(), backend.provider_name());
+            backend.delete_objects(&paths).await
+        }
+    }
+}
+
+/// Copy the local file at `path` to the same relative path under
+/// `backup_dir`, moving it there if the sync deletes it
+async fn back_up_file(backup_dir: &std::path::Path, local_dir: &std::path::Path, path: &std::path::Path, remove: bool) -> Result<()> {
+    let backup = backup_dir.join(path.strip_prefix(local_dir).unwrap_or(path));
+    info!("Backing up {} to {}", path.display(), backup.display());
+    if let Some(parent) = backup.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let moved = if remove {
+        match tokio::fs::rename(path, &backup).await {
+            Ok(()) => true,
+            // The backup directory is on another filesystem
+            Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => false,
+            Err(e) => {
+                error!("Error moving {} to {}: {}", path.display(), backup.display(), e);
+                return Err(ToolError::Io(e));
+            }
+        }
+    } else {
+        false
+    };
+    if !moved {
+        tokio::fs::copy(path, &backup).await
+            .map_err(|e| {
+                error!("Error copying {} to {}: {}", path.display(), backup.display(), e);
+                ToolError::Io(e)
+            })?;
+        if remove {
+            tokio::fs::remove_file(path).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Run `transfer` on every item with at most `limit` in flight. Items are
+/// labelled with the path reported if their transfer fails.
+pub(super) async fn run_transfers<T, F, Fut>(limit: usize, items: Vec<(String, T)>, transfer: F) -> Result<()>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let total = items.len();
+    let failures: Vec<(String, ToolError)> = stream::iter(items)
+        .map(|(label, item)| {
+            let transfer = transfer(item);
+            async move { (label, transfer.await) }
+        })
+        .buffer_unordered(limit.max(1))
+        .filter_map(|(label, result)| async move { result.err().map(|e| (label, e)) })
+        .collect()
+        .await;
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        error!("{} of {} transfers failed", failures.len(), total);
+        Err(ToolError::Transfers { total, failures })
+    }
+}
+
+/// Like run_transfers, but leaves out items the journal records as already
+/// transferred from the same source and records each item that completes.
+/// Items are labelled with their destination, which is the journal key.
+async fn run_journaled<T, F, Fut>(journal: Option<&Journal>, limit: usize, items: Vec<(String, Fingerprint, T)>, transfer: F) -> Result<()>
+where
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let Some(journal) = journal else {
+        let items = items.into_iter().map(|(label, _, item)| (label, item)).collect();
+        return run_transfers(limit, items, transfer).await;
+    };
+
+    let total = items.len();
+    let pending: Vec<_> = items.into_iter()
+        .filter(|(label, source, _)| !journal.is_done(label, source))
+        .map(|(label, source, item)| (label.clone(), (label, source, item)))
+        .collect();
+    if pending.len() < total {
+        info!("Skipping {} files already transferred by an earlier run", total - pending.len());
+    }
+
+    run_transfers(limit, pending, |(label, source, item)| {
+        let transfer = transfer(item);
+        async move {
+            transfer.await?;
+            journal.record_done(&label, source)
+        }
+    }).await
+}
+
+pub(super) async fn upload_directory<B: StorageBackend + ?Sized>(backend: &B, local_dir: &std::path::Path, remote_prefix: &str) -> Result<()> {
+    info!("Uploading directory {} to {} prefix: {}", local_dir.display(), backend.provider_name(), remote_prefix);
+    
+    let files = list_files_recursively(local_dir, backend.filter(), backend.plan()).await?;
+    let file_count = files.len();
+    let mut uploads = Vec::with_capacity(file_count);
+    for (local_path, relative_path) in files {
+        let remote_path = if remote_prefix.is_empty() {
+            relative_path.to_string_lossy().to_string()
+        } else {
+            format!("{}/{}", remote_prefix.trim_matches('/'), relative_path.to_string_lossy())
+        };
+        let source = local_fingerprint(&local_path).await?;
+        uploads.push((remote_path.clone(), source, (local_path, remote_path)));
+    }
+
+    if let Some(plan) = backend.plan() {
+        plan_copies(plan, Action::Upload, &uploads);
+        return Ok(());
+    }
+
+    run_journaled(backend.journal(), backend.transfer_options().concurrent_uploads, uploads, |(local_path, remote_path)| async move {
+        backend.upload_file(&local_path, &remote_path).await
+    }).await?;
+
+    info!("Successfully uploaded {} files from directory {}", file_count, local_dir.display());
+    Ok(())
+}
+
+pub(super) async fn download_directory<B: StorageBackend + ?Sized>(backend: &B, remote_prefix: &str, local_dir: &std::path::Path) -> Result<()> {
+    info!("Downloading {} prefix {} to directory {}", backend.provider_name(), remote_prefix, local_dir.display());
+
+    let objects = list_filtered(backend, remote_prefix, backend.filter(), backend.plan()).await?;
+    let object_count = objects.len();
+    let downloads: Vec<_> = objects.into_iter()
+        .map(|(obj, (size, modified))| {
+            let local_path = local_dir.join(relative_key(&obj, remote_prefix));
+            (obj.clone(), Fingerprint::new(size, modified), (obj, local_path))
+        })
+        .collect();
+
+    if let Some(plan) = backend.plan() {
+        for (_, source, (_, local_path)) in &downloads {
+            plan.add(Action::Download, local_path.display().to_string(), Some(source.size), COPY);
+        }
+        return Ok(());
+    }
+
+    // download_file creates missing parent directories
+    run_journaled(backend.journal(), backend.transfer_options().concurrent_uploads, downloads, |(obj, local_path)| async move {
+        backend.download_file(&obj, &local_path).await
+    }).await?;
+
+    info!("Successfully downloaded {} files to directory {}", object_count, local_dir.display());
+    Ok(())
+}
+
+pub(super) async fn copy_directory<D: StorageBackend + ?Sized>(source_backend: &dyn StorageBackend, source_prefix: &str, dest_backend: &D, dest_prefix: &str) -> Result<()> {
+    info!("Copying {} prefix {} to {} prefix {}", source_backend.provider_name(), source_prefix, dest_backend.provider_name(), dest_prefix);
+
+    let objects = list_filtered(source_backend, source_prefix, dest_backend.filter(), dest_backend.plan()).await?;
+    let object_count = objects.len();
+    let copies: Vec<_> = objects.into_iter()
+        .map(|(src_path, (size, modified))| {
+            let relative_path = src_path.strip_prefix(source_prefix)
+                .unwrap_or(&src_path)
+                .trim_start_matches('/');
+            let dest_path = if dest_prefix.is_empty() {
+                relative_path.to_string()
+            } else {
+                format!("{}/{}", dest_prefix.trim_matches('/'), relative_path)
+            };
+            (dest_path.clone(), Fingerprint::new(size, modified), (src_path, dest_path))
+        })
+        .collect();
+
+    if let Some(plan) = dest_backend.plan() {
+        plan_copies(plan, Action::Copy, &copies);
+        return Ok(());
+    }
+
+    run_journaled(dest_backend.journal(), dest_backend.transfer_options().concurrent_uploads, copies, |(src_path, dest_path)| async move {
+        dest_backend.copy_from(source_backend, &src_path, &dest_path).await
+    }).await?;
+
+    info!("Successfully copied {} objects to {}", object_count, dest_prefix);
+    Ok(())
+}
+
+pub(super) async fn remove_directory<B: StorageBackend + ?Sized>(backend: &B, prefix: &str) -> Result<()> {
+    info!("Removing {} prefix {}", backend.provider_name(), prefix);
+
+    let objects = list_filtered(backend, prefix, backend.filter(), backend.plan()).await?;
+    if let Some(plan) = backend.plan() {
+        for (key, (size, _)) in objects {
+            plan.add(Action::Delete, key, Some(size), "under the removed prefix");
+        }
+        return Ok(());
+    }
+
+    let keys: Vec<String> = objects.into_keys().collect();
+    backend.delete_objects(&keys).await?;
+
+    info!("Successfully removed {} objects under {}", keys.len(), prefix);
+    Ok(())
+}
+
+pub(super) async fn sync_directories<D: StorageBackend + ?Sized>(source_backend: &dyn StorageBackend, source: &str, dest_backend: &D, dest: &str, options: &SyncOptions) -> Result<()> {
+    info!("Syncing from {} {} to {} {}", source_backend.provider_name(), source, dest_backend.provider_name(), dest);
+    
+    let backup_dir = remote_backup_dir(options, dest)?;
+
+    // List files in source and destination
+    let (filter, plan) = (dest_backend.filter(), dest_backend.plan());
+    let source_files = list_filtered(source_backend, source, filter, plan).await?;
+    let dest_files = list_filtered(dest_backend, dest, filter, plan).await?;
+
+    // Find files to copy: missing, a different size, or changed as
+    // options.compare tells
+    let mut files_to_copy = Vec::new();
+    let mut overwrites = Vec::new();
+    let mut same_size = Vec::new();
+    let mut planned = Vec::new();
+    for (src_path, (src_size, src_time)) in &source_files {
+        // Get the relative path by removing the source prefix
+        let rel_path = src_path.strip_prefix(source)
+            .unwrap_or(src_path)
+            .trim_start_matches('/');
+
+        // Construct the destination path
+        let dest_path = if dest.is_empty() {
+            rel_path.to_string()
+        } else {
+            format!("{}/{}", dest.trim_matches('/'), rel_path)
+        };
+
+        let copy = (dest_path.clone(), Fingerprint::new(*src_size, *src_time), (src_path.clone(), dest_path.clone()));
+        match dest_files.get(&dest_path) {
+            Some((dest_size, dest_time)) if dest_size == src_size => {
+                same_size.push((copy, *src_time, *dest_time));
+            }
+            Some((dest_size, _)) => {
+                planned.push(PlanEntry::new(Action::Update, &dest_path, Some(*src_size), size_differs(*src_size, *dest_size)));
+                overwrites.push((dest_path.clone(), dest_path));
+                files_to_copy.push(copy);
+            }
+            None => {
+                // File doesn't exist in destination
+                planned.push(PlanEntry::new(Action::Copy, &dest_path, Some(*src_size), MISSING));
+                files_to_copy.push(copy);
+            }
+        }
+    }
+
+    let checked: Vec<_> = stream::iter(same_size)
+        .map(|(copy, src_time, dest_time)| async move {
+            let (src_path, dest_path) = &copy.2;
+            let current = compare::copy_is_current(source_backend, src_path, src_time, dest_backend, dest_path, dest_time, options.compare).await?;
+            Ok::<_, ToolError>((copy, current))
+        })
+        .buffer_unordered(dest_backend.transfer_options().concurrent_uploads.max(1))
+        .try_collect()
+        .await?;
+    for (copy, current) in checked {
+        planned.push(compared(&copy.0, copy.1.size, current, options));
+        if !current {
+            overwrites.push((copy.0.clone(), copy.0.clone()));
+            files_to_copy.push(copy);
+        }
+    }
+
+    // Find files that exist in destination but not in source
+    let mut deletes = Vec::new();
+    if options.delete {
+        for (dest_path, (dest_size, _)) in &dest_files {
+            // Get the relative path by removing the destination prefix
+            let rel_path = dest_path.strip_prefix(dest)
+                .unwrap_or(dest_path)
+                .trim_start_matches('/');
+
+            // Construct the source path
+            let src_path = if source.is_empty() {
+                rel_path.to_string()
+            } else {
+                format!("{}/{}", source.trim_matches('/'), rel_path)
+            };
+
+            if !source_files.contains_key(&src_path) {
+                planned.push(PlanEntry::new(Action::Delete, dest_path, Some(*dest_size), NOT_IN_SOURCE));
+                deletes.push(dest_path.clone());
+            }
+        }
+    }
+
+    check_deletes(plan, options, source, source_files.len(), dest_files.len(), deletes.len())?;
+
+    if let Some(plan) = plan {
+        plan.extend(planned);
+        return Ok(());
+    }
+
+    let limit = dest_backend.transfer_options().concurrent_uploads;
+    if let Some(backup_dir) = backup_dir {
+        run_transfers(limit, overwrites, |dest_path| async move {
+            back_up_object(dest_backend, backup_dir, dest, &dest_path, false).await
+        }).await?;
+    }
+
+    // Copy files that are missing or different
+    run_journaled(dest_backend.journal(), dest_backend.transfer_options().concurrent_uploads, files_to_copy, |(src_path, dest_path)| async move {
+        dest_backend.copy_from(source_backend, &src_path, &dest_path).await
+    }).await?;
+
+    // Delete files that exist in destination but not in source
+    delete_remote(dest_backend, backup_dir, dest, deletes).await?;
+
+    info!("Successfully synced directories");
+    Ok(())
+}
+
+/// Size and modification time of a local file, as recorded in the journal
+async fn local_fingerprint(path: &std::path::Path) -> Result<Fingerprint> {
+    let metadata = tokio::fs::metadata(path).await
+        .map_err(|e| {
+            error!("Error getting file metadata: {}", e);
+            ToolError::Io(e)
+        })?;
+    Ok(Fingerprint::from_metadata(&metadata))
+}
+
+pub(super) async fn sync_local_to_remote<B: StorageBackend + ?Sized>(backend: &B, local_dir: &std::path::Path, remote_prefix: &str, options: &SyncOptions) -> Result<()> {
+    info!("Syncing from local {} to remote {}", local_dir.display(), remote_prefix);
+    
+    let backup_dir = remote_backup_dir(options, remote_prefix)?;
+
+    // List files in source (local) and destination (remote)
+    let local_files = list_files_recursively(local_dir, backend.filter(), backend.plan()).await?;
+    let remote_files = list_filtered(backend, remote_prefix, backend.filter(), backend.plan()).await?;
+
+    // Convert local files to a map of relative path -> (size, mtime) for comparison
+    let mut local_files_map = HashMap::new();
+    for (local_path, rel_path) in local_files {
+        let metadata = tokio::fs::metadata(&local_path).await
+            .map_err(|e| {
+                error!("Error getting file metadata: {}", e);
+                ToolError::Io(e)
+            })?;
+        
+        let mtime = metadata.modified()
+            .map_err(|e| {
+                error!("Error getting file mtime: {}", e);
+                ToolError::Io(e)
+            })?;
+
+        local_files_map.insert(
+            rel_path.to_string_lossy().to_string(),
+            (metadata.len(), mtime)
+        );
+    }
+
+    // Find files to copy: missing, a different size, or changed as
+    // options.compare tells
+    let mut uploads = Vec::new();
+    let mut overwrites = Vec::new();
+    let mut same_size = Vec::new();
+    let mut planned = Vec::new();
+    for (rel_path, (local_size, local_time)) in &local_files_map {
+        let remote_path = if remote_prefix.is_empty() {
+            rel_path.clone()
+        } else {
+            format!("{}/{}", remote_prefix.trim_matches('/'), rel_path)
+        };
+
+        let upload = (remote_path.clone(), Fingerprint::new(*local_size, *local_time), (local_dir.join(rel_path), remote_path.clone()));
+        match remote_files.get(&remote_path) {
+            Some((remote_size, remote_time)) if remote_size == local_size => {
+                same_size.push((upload, *local_time, *remote_time));
+            }
+            Some((remote_size, _)) => {
+                info!("Updating {} in remote storage", remote_path);
+                planned.push(PlanEntry::new(Action::Update, &remote_path, Some(*local_size), size_differs(*local_size, *remote_size)));
+                overwrites.push((remote_path.clone(), remote_path));
+                uploads.push(upload);
+            }
+            None => {
+                // File doesn't exist in destination
+                info!("Copying {} to remote storage", remote_path);
+                planned.push(PlanEntry::new(Action::Upload, &remote_path, Some(*local_size), MISSING));
+                uploads.push(upload);
+            }
+        }
+    }
+
+    let checked: Vec<_> = stream::iter(same_size)
+        .map(|(upload, local_time, remote_time)| async move {
+            let (local_path, remote_path) = &upload.2;
+            let current = compare::upload_is_current(backend, options.compare, local_path, local_time, remote_path, remote_time).await?;
+            Ok::<_, ToolError>((upload, current))
+        })
+        .buffer_unordered(backend.transfer_options().concurrent_uploads.max(1))
+        .try_collect()
+        .await?;
+    for (upload, current) in checked {
+        planned.push(compared(&upload.0, upload.1.size, current, options));
+        if !current {
+            info!("Updating {} in remote storage", upload.0);
+            overwrites.push((upload.0.clone(), upload.0.clone()));
+            uploads.push(upload);
+        }
+    }
+
+    // Find remote files that don't exist locally
+    let mut deletes = Vec::new();
+    if options.delete {
+        for (remote_path, (remote_size, _)) in &remote_files {
+            let rel_path = remote_path.strip_prefix(remote_prefix)
+                .unwrap_or(remote_path)
+                .trim_start_matches('/');
+
+            if !local_files_map.contains_key(rel_path) {
+                planned.push(PlanEntry::new(Action::Delete, remote_path, Some(*remote_size), NOT_IN_SOURCE));
+                deletes.push(remote_path.clone());
+            }
+        }
+    }
+
+    check_deletes(backend.plan(), options, &local_dir.display().to_string(), local_files_map.len(), remote_files.len(), deletes.len())?;
+
+    if let Some(plan) = backend.plan() {
+        plan.extend(planned);
+        return Ok(());
+    }
+
+    let limit = backend.transfer_options().concurrent_uploads;
+    if let Some(backup_dir) = backup_dir {
+        run_transfers(limit, overwrites, |remote_path| async move {
+            back_up_object(backend, backup_dir, remote_prefix, &remote_path, false).await
+        }).await?;
+    }
+
+    run_journaled(backend.journal(), limit, uploads, |(local_path, remote_path)| async move {
+        backend.upload_file(&local_path, &remote_path).await
+    }).await?;
+
+    // Delete remote files that don't exist locally
+    delete_remote(backend, backup_dir, remote_prefix, deletes).await?;
+
+    info!("Successfully synced from local to remote");
+    Ok(())
+}
+
+pub(super) async fn sync_remote_to_local<B: StorageBackend + ?Sized>(backend: &B, remote_prefix: &str, local_dir: &std::path::Path, options: &SyncOptions) -> Result<()> {
+    info!("Syncing from remote {} to local {}", remote_prefix, local_dir.display());
+    
+    let backup_dir = local_backup_dir(options, local_dir)?;
+    let backup_dir = backup_dir.as_deref();
+
+    // List files in source (remote) and destination (local)
+    let remote_files = list_filtered(backend, remote_prefix, backend.filter(), backend.plan()).await?;
+    
+    // Create local directory if it doesn't exist; a dry run only reads
+    if backend.plan().is_none() {
+        tokio::fs::create_dir_all(local_dir).await
+            .map_err(|e| {
+                error!("Error creating directory: {}", e);
+                ToolError::Io(e)
+            })?;
+    }
+    
+    let local_files = if local_dir.exists() {
+        list_files_recursively(local_dir, backend.filter(), backend.plan()).await?
+    } else {
+        Vec::new()
+    };
+
+    // Convert local files to a map of relative path -> (size, mtime) for comparison
+    let mut local_files_map = HashMap::new();
+    for (local_path, rel_path) in local_files {
+        let metadata = tokio::fs::metadata(&local_path).await
+            .map_err(|e| {
+                error!("Error getting file metadata: {}", e);
+                ToolError::Io(e)
+            })?;
+        
+        let mtime = metadata.modified()
+            .map_err(|e| {
+                error!("Error getting file mtime: {}", e);
+                ToolError::Io(e)
+            })?;
+
+        local_files_map.insert(
+            rel_path.to_string_lossy().to_string(),
+            (local_path, metadata.len(), mtime)
+        );
+    }
+
+    // Find files to copy: missing, a different size, or changed as
+    // options.compare tells
+    let mut downloads = Vec::new();
+    let mut overwrites = Vec::new();
+    let mut same_size = Vec::new();
+    let mut planned = Vec::new();
+    for (remote_path, (remote_size, remote_time)) in &remote_files {
+        let rel_path = remote_path.strip_prefix(remote_prefix)
+            .unwrap_or(remote_path)
+            .trim_start_matches('/');
+
+        let local_path = local_dir.join(rel_path);
+        let download = (remote_path.clone(), Fingerprint::new(*remote_size, *remote_time), (remote_path.clone(), local_path));
+        let label = download.2.1.display().to_string();
+
+        match local_files_map.get(rel_path) {
+            Some((_, local_size, local_time)) if local_size == remote_size => {
+                same_size.push((download, *local_time, *remote_time));
+            }
+            Some((_, local_size, _)) => {
+                info!("Updating {} in local storage", label);
+                planned.push(PlanEntry::new(Action::Update, label.clone(), Some(*remote_size), size_differs(*remote_size, *local_size)));
+                overwrites.push((label, download.2.1.clone()));
+                downloads.push(download);
+            }
+            None => {
+                // File doesn't exist locally
+                info!("Copying {} to local storage", label);
+                planned.push(PlanEntry::new(Action::Download, label, Some(*remote_size), MISSING));
+                downloads.push(download);
+            }
+        }
+    }
+
+    let checked: Vec<_> = stream::iter(same_size)
+        .map(|(download, local_time, remote_time)| async move {
+            let (remote_path, local_path) = &download.2;
+            let current = compare::download_is_current(backend, options.compare, remote_path, remote_time, local_path, local_time).await?;
+            Ok::<_, ToolError>((download, current))
+        })
+        .buffer_unordered(backend.transfer_options().concurrent_uploads.max(1))
+        .try_collect()
+        .await?;
+    for (download, current) in checked {
+        planned.push(compared(&download.2.1.display().to_string(), download.1.size, current, options));
+        if !current {
+            info!("Updating {} in local storage", download.2.1.display());
+            overwrites.push((download.2.1.display().to_string(), download.2.1.clone()));
+            downloads.push(download);
+        }
+    }
+
+    // Find local files that don't exist in remote
+    let local_files_count = local_files_map.len();
+    let mut deletes = Vec::new();
+    if options.delete {
+        for (rel_path, (local_path, local_size, _)) in local_files_map {
+            let remote_path = if remote_prefix.is_empty() {
+                rel_path
+            } else {
+                format!("{}/{}", remote_prefix.trim_matches('/'), rel_path)
+            };
+
+            if !remote_files.contains_key(&remote_path) {
+                planned.push(PlanEntry::new(Action::Delete, local_path.display().to_string(), Some(local_size), NOT_IN_SOURCE));
+                deletes.push((local_path.display().to_string(), local_path));
+            }
+        }
+    }
+
+    check_deletes(backend.plan(), options, remote_prefix, remote_files.len(), local_files_count, deletes.len())?;
+
+    if let Some(plan) = backend.plan() {
+        plan.extend(planned);
+        return Ok(());
+    }
+
+    let limit = backend.transfer_options().concurrent_uploads;
+    if let Some(backup_dir) = backup_dir {
+        run_transfers(limit, overwrites, |local_path| async move {
+            back_up_file(backup_dir, local_dir, &local_path, false).await
+        }).await?;
+    }
+
+    run_journaled(backend.journal(), limit, downloads, |(remote_path, local_path)| async move {
+        backend.download_file(&remote_path, &local_path).await
+    }).await?;
+
+    // Delete local files that don't exist in remote
+    run_transfers(limit, deletes, |local_path| async move {
+        if let Some(backup_dir) = backup_dir {
+            return back_up_file(backup_dir, local_dir, &local_path, true).await;
+        }
+        info!("Deleting {}", local_path.display());
+        tokio::fs::remove_file(&local_path).await
+            .map_err(|e| {
+                error!("Error deleting file: {}", e);
+                ToolError::Io(e)
+            })
+    }).await?;
+
+    info!("Successfully synced from remote to local");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, FaultInjection, StorageConfig, StorageProvider, TransferOptions};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::journal::Resume;
+    use crate::storage::TransferContext;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Small chunks, so files of a few KiB take the multipart upload and
+    /// ranged download paths, one transfer at a time, so a seeded injector
+    /// replays the same faults on every run
+    fn transfer_options() -> TransferOptions {
+        TransferOptions {
+            concurrent_uploads: 1,
+            concurrent_parts: 1,
+            chunk_size: 1024,
+            retry_attempts: 10,
+            retry_base_delay_ms: 1,
+            retry_max_delay_ms: 5,
+            ..Config::default().transfer_options
+        }
+    }
+
+    fn memory(faults: Option<FaultInjection>) -> MemoryStorage {
+        memory_with(faults, &TransferContext::default())
+    }
+
+    fn memory_with(faults: Option<FaultInjection>, context: &TransferContext) -> MemoryStorage {
+        let config = StorageConfig {
+            provider: StorageProvider::Memory,
+            fault_injection: faults,
+            ..Default::default()
+        };
+        MemoryStorage::new(&config, &transfer_options(), context)
+    }
+
+    fn seeded_faults(seed: u64) -> FaultInjection {
+        FaultInjection {
+            throttle_probability: 0.05,
+            failure_probability: 0.15,
+            truncate_probability: 0.1,
+            seed: Some(seed),
+            ..Default::default()
+        }
+    }
+
+    fn write(dir: &Path, path: &str, content: &[u8]) {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        write(dir.path(), "config.json", b"{\"lr\": 0.001}");
+        write(dir.path(), "checkpoints/step-100.bin", &(0..5000u32).map(|i| (i % 256) as u8).collect::<Vec<_>>());
+        write(dir.path(), "checkpoints/step-200.bin", &(0..3000u32).map(|i| (i * 7 % 256) as u8).collect::<Vec<_>>());
+        write(dir.path(), "logs/train.log", b"epoch 1 loss 0.5\nepoch 2 loss 0.3\n");
+        dir
+    }
+
+    fn assert_same_files(expected: &Path, actual: &Path, paths: &[&str]) {
+        for path in paths {
+            assert_eq!(std::fs::read(expected.join(path)).unwrap(), std::fs::read(actual.join(path)).unwrap(), "{} differs", path);
+        }
+    }
+
+    const TREE: [&str; 4] = ["config.json", "checkpoints/step-100.bin", "checkpoints/step-200.bin", "logs/train.log"];
+
+    #[tokio::test]
+    async fn sync_round_trips_through_faulty_store() {
+        let source = tree();
+        let dest = tempfile::tempdir().unwrap();
+        let remote = memory(Some(seeded_faults(42)));
+        let options = SyncOptions::default();
+
+        remote.sync_local_to_remote(source.path(), "runs/exp1", &options).await.unwrap();
+        let listed = remote.list_files_with_metadata("runs/exp1").await.unwrap();
+        let mut keys: Vec<_> = listed.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["runs/exp1/checkpoints/step-100.bin", "runs/exp1/checkpoints/step-200.bin", "runs/exp1/config.json", "runs/exp1/logs/train.log"]);
+
+        remote.sync_remote_to_local("runs/exp1", dest.path(), &options).await.unwrap();
+        assert_same_files(source.path(), dest.path(), &TREE);
+        assert!(!dest.path().join("checkpoints/step-100.bin.mytool-part").exists());
+    }
+
+    #[tokio::test]
+    async fn sync_local_to_remote_skips_unchanged_files_and_deletes_removed_ones() {
+        let source = tree();
+        let remote = memory(None);
+        remote.sync_local_to_remote(source.path(), "runs", &SyncOptions::default()).await.unwrap();
+        let before = remote.list_files_with_metadata("runs").await.unwrap();
+
+        std::fs::remove_file(source.path().join("logs/train.log")).unwrap();
+        write(source.path(), "config.json", b"{\"lr\": 0.0001}");
+        let options = SyncOptions { delete: true, ..Default::default() };
+        remote.sync_local_to_remote(source.path(), "runs", &options).await.unwrap();
+        let after = remote.list_files_with_metadata("runs").await.unwrap();
+
+        assert!(!after.contains_key("runs/logs/train.log"));
+        assert_eq!(after["runs/config.json"].0, 14);
+        // Rewriting an unchanged object would give it a new modification time
+        assert_eq!(after["runs/checkpoints/step-100.bin"], before["runs/checkpoints/step-100.bin"]);
+        assert_eq!(after["runs/checkpoints/step-200.bin"], before["runs/checkpoints/step-200.bin"]);
+    }
+
+    #[tokio::test]
+    async fn sync_remote_to_local_deletes_only_with_delete() {
+        let source = tree();
+        let dest = tempfile::tempdir().unwrap();
+        let remote = memory(Some(seeded_faults(7)));
+        remote.sync_local_to_remote(source.path(), "runs", &SyncOptions::default()).await.unwrap();
+        write(dest.path(), "stale.txt", b"left over");
+
+        remote.sync_remote_to_local("runs", dest.path(), &SyncOptions::default()).await.unwrap();
+        assert!(dest.path().join("stale.txt").exists());
+
+        let options = SyncOptions { delete: true, ..Default::default() };
+        remote.sync_remote_to_local("runs", dest.path(), &options).await.unwrap();
+        assert!(!dest.path().join("stale.txt").exists());
+        assert_same_files(source.path(), dest.path(), &TREE);
+    }
+
+    #[tokio::test]
+    async fn sync_refuses_to_empty_the_destination() {
+        let source = tree();
+        let empty = tempfile::tempdir().unwrap();
+        let remote = memory(None);
+        remote.sync_local_to_remote(source.path(), "runs", &SyncOptions::default()).await.unwrap();
+
+        let options = SyncOptions { delete: true, ..Default::default() };
+        let result = remote.sync_local_to_remote(empty.path(), "runs", &options).await;
+        assert!(matches!(result, Err(ToolError::InvalidOperation(_))));
+        assert_eq!(remote.list_files_with_metadata("runs").await.unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn deletes_more_keys_than_one_delete_request_takes() {
+        let remote = memory(None);
+        let keys: Vec<String> = (0..2500).map(|i| format!("runs/{:04}.bin", i)).collect();
+        for key in keys.iter().chain([&"kept/0000.bin".to_string()]) {
+            remote.store().put(&object_store::path::Path::from(key.as_str()), bytes::Bytes::from_static(b"x")).await.unwrap();
+        }
+
+        remote.delete_objects(&keys[..1200]).await.unwrap();
+        let listed = remote.list_files_with_metadata("runs").await.unwrap();
+        assert_eq!(listed.len(), 1300);
+        assert!(!listed.contains_key("runs/1199.bin") && listed.contains_key("runs/1200.bin"));
+
+        remote.remove_directory("runs").await.unwrap();
+        assert!(remote.list_files_with_metadata("runs").await.unwrap().is_empty());
+        assert_eq!(remote.list_objects("kept").await.unwrap(), ["kept/0000.bin"]);
+    }
+
+    #[tokio::test]
+    async fn download_directory_strips_the_prefix_once() {
+        let source = tree();
+        let dest = tempfile::tempdir().unwrap();
+        let remote = memory(None);
+        remote.upload_directory(source.path(), "runs/runs").await.unwrap();
+
+        remote.download_directory("runs/", dest.path()).await.unwrap();
+        assert_same_files(source.path(), &dest.path().join("runs"), &TREE);
+    }
+
+    #[tokio::test]
+    async fn resumed_download_with_delete_keeps_its_partial_file() {
+        let source = tree();
+        let dest = tempfile::tempdir().unwrap();
+        let journals = tempfile::tempdir().unwrap();
+        let journal = Arc::new(Journal::open(journals.path(), "sync runs dest", Resume::Auto).unwrap());
+        let context = TransferContext { journal: Some(journal.clone()), ..Default::default() };
+        let remote = memory_with(None, &context);
+        remote.sync_local_to_remote(source.path(), "runs", &SyncOptions::default()).await.unwrap();
+
+        // An earlier run fetched the first range of a ranged download, then died
+        let content = std::fs::read(source.path().join("checkpoints/step-100.bin")).unwrap();
+        let local_path = dest.path().join("checkpoints/step-100.bin");
+        let partial = dest.path().join("checkpoints/step-100.bin.mytool-part");
+        let mut written = content[..1024].to_vec();
+        written.resize(content.len(), 0);
+        write(dest.path(), "checkpoints/step-100.bin.mytool-part", &written);
+        let (size, modified) = remote.list_files_with_metadata("runs").await.unwrap()["runs/checkpoints/step-100.bin"];
+        journal.record_range(&local_path.to_string_lossy(), Fingerprint::new(size, modified), 0..1024).unwrap();
+
+        let options = SyncOptions { delete: true, ..Default::default() };
+        remote.sync_remote_to_local("runs", dest.path(), &options).await.unwrap();
+        assert_same_files(source.path(), dest.path(), &TREE);
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn check_deletes_allows_deletes_within_the_limits() {
+        let options = SyncOptions { delete: true, max_delete: Some(3), max_delete_percent: Some(50), ..Default::default() };
+        assert!(check_deletes(None, &options, "src", 10, 6, 3).is_ok());
+        assert!(check_deletes(None, &options, "src", 0, 6, 0).is_ok());
+        assert!(check_deletes(None, &SyncOptions::default(), "src", 1, 1000, 999).is_ok());
+    }
+
+    #[test]
+    fn check_deletes_refuses_deletes_beyond_the_limits() {
+        let refusal = |options: &SyncOptions, source_count, dest_count, deletes| {
+            match check_deletes(None, options, "src", source_count, dest_count, deletes) {
+                Err(ToolError::InvalidOperation(message)) => message,
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        assert!(refusal(&SyncOptions::default(), 0, 5, 5).contains("the source src listed no files"));
+        let options = SyncOptions { max_delete: Some(3), ..Default::default() };
+        assert!(refusal(&options, 10, 10, 4).contains("--max-delete is 3"));
+        let options = SyncOptions { max_delete_percent: Some(50), ..Default::default() };
+        assert!(refusal(&options, 10, 6, 4).contains("more than 50% of the 6 files"));
+    }
+
+    #[test]
+    fn check_deletes_only_warns_in_a_dry_run() {
+        let options = SyncOptions { max_delete: Some(1), ..Default::default() };
+        assert!(check_deletes(Some(&Plan::new()), &options, "src", 0, 5, 5).is_ok());
+    }
+}
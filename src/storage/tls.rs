@@ -0,0 +1,192 @@
+//! TLS Configuration Module
+//!
+//! Builds the HTTPS connector for the AWS SDK client when a remote sets
+//! `allow_insecure`, which skips certificate verification entirely (local
+//! testing against self-signed endpoints only), or when a `ca_bundle` of
+//! extra root certificates is in use. object_store's client applies
+//! `allow_insecure` through its ClientOptions.
+//!
+//! object_store cannot be handed root certificates, but its client also
+//! trusts the native roots, which are read from `SSL_CERT_FILE` when that
+//! is set. The bundle is therefore pointed to by `SSL_CERT_FILE` before the
+//! runtime starts, and applies to every remote of the command; remotes may
+//! not name different bundles. The SDK client of every remote trusts the
+//! public roots plus the bundle, like object_store's.
+//!
+//! Remotes keep the SDK's default connector when neither setting is used.
+
+use aws_smithy_client::conns::Https;
+use aws_smithy_client::hyper_ext::Adapter;
+use crate::config::{Config, StorageConfig};
+use crate::error::{Result, ToolError};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// Environment variable the native root store is read from
+const CERT_FILE_VAR: &str = "SSL_CERT_FILE";
+
+/// The `ca_bundle` in use, and its certificates
+static CA_BUNDLE: OnceLock<(PathBuf, Vec<Vec<u8>>)> = OnceLock::new();
+
+/// Trust the `ca_bundle` of the configured remotes in every client.
+/// Changing the environment is only sound while no other thread runs, so
+/// this is called before the async runtime starts.
+pub fn set_ca_bundle(config: &Config) -> Result<()> {
+    let mut bundles: Vec<&Path> = std::iter::once(&config.default_storage)
+        .chain(config.remotes.values())
+        .filter_map(|storage| storage.ca_bundle.as_deref())
+        .collect();
+    bundles.sort();
+    bundles.dedup();
+
+    match bundles.as_slice() {
+        [] => Ok(()),
+        [path] => {
+            let certificates = read_certificates(path)?;
+            if std::env::var_os(CERT_FILE_VAR).is_some() {
+                warn!("ca_bundle {} replaces {} for this command", path.display(), CERT_FILE_VAR);
+            }
+            info!("Trusting {} extra root certificates from {}", certificates.len(), path.display());
+            std::env::set_var(CERT_FILE_VAR, path);
+            let _ = CA_BUNDLE.set((path.to_path_buf(), certificates));
+            Ok(())
+        }
+        _ => Err(ToolError::Config(format!(
+            "Remotes use different ca_bundle files ({}); put their certificates in one file",
+            bundles.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")
+        ))),
+    }
+}
+
+/// DER certificates of a PEM bundle, which must hold at least one
+fn read_certificates(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| ToolError::Config(format!("Cannot read ca_bundle {}: {}", path.display(), e)))?;
+    let certificates = rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+        .map_err(|e| ToolError::Config(format!("Cannot parse ca_bundle {}: {}", path.display(), e)))?;
+    if certificates.is_empty() {
+        return Err(ToolError::Config(format!("ca_bundle {} holds no PEM certificates", path.display())));
+    }
+    Ok(certificates)
+}
+
+/// Connector for the SDK client, or None when the defaults apply
+pub fn sdk_connector(config: &StorageConfig) -> Option<Adapter<Https>> {
+    let tls = if config.allow_insecure {
+        // No roots are needed, as the verifier accepts every certificate
+        warn!("TLS certificate verification is disabled for this remote");
+        let mut tls = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        tls.dangerous().set_certificate_verifier(Arc::new(NoCertificateVerification));
+        tls
+    } else {
+        let (_, certificates) = CA_BUNDLE.get()?;
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots(certificates))
+            .with_no_client_auth()
+    };
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+
+    Some(Adapter::builder().build(https))
+}
+
+/// The public roots and `extra` certificates
+fn roots(extra: &[Vec<u8>]) -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    let (added, skipped) = roots.add_parsable_certificates(extra);
+    if skipped > 0 {
+        warn!("Skipped {} unusable certificates of the ca_bundle ({} added)", skipped, added);
+    }
+    roots
+}
+
+/// Accepts any server certificate; used only for `allow_insecure`
+struct NoCertificateVerification;
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Self-signed CA certificate generated for these tests
+    const CA_PEM: &str = "\
+-----BEGIN CERTIFICATE-----\n\
+MIIBiDCCAS+gAwIBAgIUJvkAnW5iVRkoX+BzQ2ToSNL4vYQwCgYIKoZIzj0EAwIw\n\
+GTEXMBUGA1UEAwwObXl0b29sIHRlc3QgQ0EwIBcNMjYxMDE2MjMxNDM5WhgPMjEy\n\
+NjA5MjIyMzE0MzlaMBkxFzAVBgNVBAMMDm15dG9vbCB0ZXN0IENBMFkwEwYHKoZI\n\
+zj0CAQYIKoZIzj0DAQcDQgAE++LU3JVpVDAIpQYon1dJX/lW8yuXHOf9jATTmT+h\n\
+c9/luQGSjl5DlJ/w1aLR73r7mwcspLByPUold2A8M2fs/qNTMFEwHQYDVR0OBBYE\n\
+FMtIF3C31dfGvYhn5gCciCeqdoVuMB8GA1UdIwQYMBaAFMtIF3C31dfGvYhn5gCc\n\
+iCeqdoVuMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDRwAwRAIgD6vYAkR/\n\
+71sGAe2KHQO/cL1WLZtAXL3ttinWAp4VKyUCIHHTQ09eYLgwI+gYF/2KAXXbcril\n\
+wipezVIIqFGoPgtP\n\
+-----END CERTIFICATE-----\n\
+";
+
+    fn bundle(content: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn reads_the_certificates_of_a_bundle() {
+        let file = bundle(&format!("# internal CA\n{}{}", CA_PEM, CA_PEM));
+        assert_eq!(read_certificates(file.path()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_bundles_without_certificates() {
+        let file = bundle("not a certificate\n");
+        assert!(matches!(read_certificates(file.path()), Err(ToolError::Config(_))));
+        assert!(matches!(read_certificates(Path::new("/nonexistent/ca.pem")), Err(ToolError::Config(_))));
+    }
+
+    #[test]
+    fn roots_add_the_bundle_to_the_public_roots() {
+        let file = bundle(CA_PEM);
+        let roots = roots(&read_certificates(file.path()).unwrap());
+        assert_eq!(roots.len(), webpki_roots::TLS_SERVER_ROOTS.len() + 1);
+    }
+
+    #[test]
+    fn remotes_may_not_name_different_bundles() {
+        let mut config = Config::default();
+        config.default_storage.ca_bundle = Some(PathBuf::from("/etc/ssl/a.pem"));
+        config.remotes.insert("minio".into(), StorageConfig {
+            ca_bundle: Some(PathBuf::from("/etc/ssl/b.pem")),
+            ..Default::default()
+        });
+        assert!(matches!(set_ca_bundle(&config), Err(ToolError::Config(_))));
+    }
+}
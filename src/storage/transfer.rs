@@ -0,0 +1,455 @@
+//! Single-File Transfer Module
+//!
+//! Provider-independent implementations of the single-file operations of
+//! `StorageBackend`. Files are streamed in `transfer_options.chunk_size`
+//! pieces, so memory use does not grow with the file size.
+//!
+//! Uploads:
+//! - Files up to one chunk are sent with a single put
+//! - Larger files are streamed through the store's multipart writer
+//! - A failed multipart upload is aborted so no orphaned parts are left behind,
+//!   then retried from the start
+//!
+//! Downloads:
+//! - Objects up to one chunk are streamed straight to disk, restarting the
+//!   stream if it breaks
+//! - Larger objects are split into `chunk_size` byte ranges that are fetched
+//!   `concurrent_parts` at a time and written at their offsets into a
+//!   preallocated file, so memory use is bounded by the ranges in flight
+//! - Data is written to `<name>.mytool-part` and renamed into place once
+//!   complete; a failed download removes the partial file
+//! - If the object carries the SHA-256 of the file it was uploaded from, the
+//!   partial file must match it before it is renamed: streamed downloads are
+//!   hashed as they are written, ranged ones once complete. A mismatch fails
+//!   with ToolError::ChecksumMismatch and removes the partial file, so a
+//!   corrupt download never replaces the file at the destination.
+//! - The mtime and mode stored with the object are restored on the file
+//!
+//! Copies between backends:
+//! - Within one store the service copies the object itself
+//! - Otherwise objects up to one chunk are read and written in one request,
+//!   and larger ones are piped as `chunk_size` ranges, `concurrent_parts` at
+//!   a time, into the destination's multipart writer, so only the chunks in
+//!   flight are held in memory
+//! - Data streamed from an object that carries a SHA-256 is hashed on the
+//!   way through; a mismatch fails before the write completes, so a corrupt
+//!   copy is never stored at the destination
+//!
+//! Resuming:
+//! When a journal is recorded, every finished range of a large download is
+//! noted and a failed download keeps its partial file, so the next run only
+//! fetches the missing ranges of an unchanged object. Multipart uploads
+//! through object_store cannot be continued and start over.
+
+use super::journal::Fingerprint;
+use super::metadata::{sha256_file, FileMetadata};
+use super::retry::{retry, OperationKind, RetryPolicy};
+use super::StorageBackend;
+use crate::error::{Result, ToolError};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use object_store::path::Path as ObjectPath;
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{info, error, warn, debug};
+
+pub(super) async fn upload_file<B: StorageBackend + ?Sized>(backend: &B, local_path: &Path, remote_path: &str) -> Result<()> {
+    info!("Uploading file to {}: {}", backend.provider_name(), remote_path);
+    let chunk_size = backend.transfer_options().chunk_size.max(1);
+
+    let mut file = tokio::fs::File::open(local_path).await
+        .map_err(|e| {
+            error!("Error opening file {}: {}", local_path.display(), e);
+            ToolError::Io(e)
+        })?;
+    let size = file.metadata().await
+        .map_err(|e| {
+            error!("Error getting file metadata: {}", e);
+            ToolError::Io(e)
+        })?
+        .len();
+
+    let remote = ObjectPath::from(remote_path);
+
+    if size <= chunk_size as u64 {
+        let mut contents = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut contents).await
+            .map_err(|e| {
+                error!("Error reading file: {}", e);
+                ToolError::Io(e)
+            })?;
+
+        backend.store().put(&remote, contents.into()).await
+            .map_err(|e| {
+                error!("Error uploading file to {}: {}", backend.provider_name(), e);
+                ToolError::Storage(e)
+            })?;
+    } else {
+        drop(file);
+        // A failed multipart writer cannot resume, so the whole upload is retried
+        let policy = RetryPolicy::from_options(backend.transfer_options());
+        retry(&policy, OperationKind::File, &format!("multipart upload {}", remote_path), || {
+            upload_multipart(backend, local_path, &remote, chunk_size)
+        }).await?;
+    }
+
+    info!("Successfully uploaded file to {}: {}", backend.provider_name(), remote_path);
+    Ok(())
+}
+
+/// Stream a file through the store's multipart writer, aborting on failure
+async fn upload_multipart<B: StorageBackend + ?Sized>(backend: &B, local_path: &Path, remote: &ObjectPath, chunk_size: usize) -> Result<()> {
+    let mut file = tokio::fs::File::open(local_path).await
+        .map_err(|e| {
+            error!("Error opening file {}: {}", local_path.display(), e);
+            ToolError::Io(e)
+        })?;
+
+    let (multipart_id, mut writer) = backend.store().put_multipart(remote).await
+        .map_err(|e| {
+            error!("Error starting multipart upload to {}: {}", backend.provider_name(), e);
+            ToolError::Storage(e)
+        })?;
+
+    let mut buffer = vec![0u8; chunk_size];
+    let result: std::io::Result<()> = async {
+        loop {
+            let read = file.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..read]).await?;
+        }
+        writer.shutdown().await
+    }.await;
+
+    if let Err(e) = result {
+        error!("Error uploading file to {}: {}", backend.provider_name(), e);
+        if let Err(abort_error) = backend.store().abort_multipart(remote, &multipart_id).await {
+            warn!("Failed to abort multipart upload of {}: {}", remote, abort_error);
+        }
+        return Err(ToolError::Io(e));
+    }
+
+    Ok(())
+}
+
+pub(super) async fn download_file<B: StorageBackend + ?Sized>(backend: &B, remote_path: &str, local_path: &Path) -> Result<()> {
+    info!("Downloading file from {}: {}", backend.provider_name(), remote_path);
+    let remote = ObjectPath::from(remote_path);
+    let meta = backend.store().head(&remote).await
+        .map_err(|e| {
+            error!("Error downloading file from {}: {}", backend.provider_name(), e);
+            ToolError::Storage(e)
+        })?;
+
+    if let Some(parent) = local_path.parent() {
+        tokio::fs::create_dir_all(parent).await
+            .map_err(|e| {
+                error!("Error creating directory: {}", e);
+                ToolError::Io(e)
+            })?;
+    }
+
+    let attributes = backend.file_metadata(remote_path).await?.unwrap_or_default();
+    let partial = partial_path(local_path);
+    let ranged = meta.size > backend.transfer_options().chunk_size.max(1);
+    let result = if ranged {
+        let source = Fingerprint::new(meta.size as u64, meta.last_modified.into());
+        download_ranges(backend, &remote, local_path, &partial, source).await.map(|()| None)
+    } else {
+        // A broken stream cannot resume, so the whole download is retried
+        let policy = RetryPolicy::from_options(backend.transfer_options());
+        retry(&policy, OperationKind::File, &format!("download {}", remote_path), || {
+            download_whole(backend, &remote, &partial)
+        }).await.map(Some)
+    };
+    let result = match (result, &attributes.sha256) {
+        (Ok(streamed_hash), Some(expected)) => verify_sha256(remote_path, &partial, expected, streamed_hash).await,
+        (result, _) => result.map(|_| ()),
+    };
+
+    if let Err(e) = result {
+        let corrupt = matches!(e, ToolError::ChecksumMismatch { .. });
+        if ranged && backend.journal().is_some() && !corrupt {
+            info!("Keeping {} so the download can be resumed", partial.display());
+        } else if let Err(remove_error) = tokio::fs::remove_file(&partial).await {
+            debug!("Could not remove partial file {}: {}", partial.display(), remove_error);
+        }
+        return Err(e);
+    }
+
+    tokio::fs::rename(&partial, local_path).await
+        .map_err(|e| {
+            error!("Error moving {} into place: {}", partial.display(), e);
+            ToolError::Io(e)
+        })?;
+
+    // Restore the source file's attributes; without them the file takes the
+    // object's modification time, which later syncs compare against
+    FileMetadata {
+        mtime: attributes.mtime.or(Some(meta.last_modified.into())),
+        ..attributes
+    }.apply(local_path).await?;
+
+    info!("Successfully downloaded file from {}: {}", backend.provider_name(), remote_path);
+    Ok(())
+}
+
+pub(super) async fn copy_from<B, S>(backend: &B, source: &S, src_path: &str, dest_path: &str) -> Result<()>
+where
+    B: StorageBackend + ?Sized,
+    S: StorageBackend + ?Sized,
+{
+    info!("Copying {} {} to {} {}", source.provider_name(), src_path, backend.provider_name(), dest_path);
+    let src = ObjectPath::from(src_path);
+    let dest = ObjectPath::from(dest_path);
+
+    if Arc::ptr_eq(source.store(), backend.store()) {
+        backend.store().copy(&src, &dest).await
+            .map_err(|e| {
+                error!("Error copying {} to {} in {}: {}", src_path, dest_path, backend.provider_name(), e);
+                ToolError::Storage(e)
+            })?;
+        info!("Successfully copied {} to {}", src_path, dest_path);
+        return Ok(());
+    }
+
+    let meta = source.store().head(&src).await
+        .map_err(|e| {
+            error!("Error reading {} from {}: {}", src_path, source.provider_name(), e);
+            ToolError::Storage(e)
+        })?;
+    let expected = source.file_metadata(src_path).await?.and_then(|attributes| attributes.sha256);
+
+    // Neither a broken read stream nor a failed writer can resume, so the
+    // whole copy is retried
+    let policy = RetryPolicy::from_options(backend.transfer_options());
+    if meta.size <= backend.transfer_options().chunk_size.max(1) {
+        retry(&policy, OperationKind::File, &format!("copy {}", src_path), || async {
+            let data = source.store().get(&src).await?.bytes().await?;
+            if let Some(expected) = &expected {
+                check_sha256(src_path, expected, hex::encode(Sha256::digest(&data)))?;
+            }
+            backend.store().put(&dest, data).await?;
+            Ok(())
+        }).await
+            .map_err(|e: ToolError| {
+                error!("Error copying {} to {}: {}", src_path, dest_path, e);
+                e
+            })?;
+    } else {
+        retry(&policy, OperationKind::File, &format!("copy {}", src_path), || {
+            pipe_multipart(backend, source, &src, &dest, meta.size, expected.as_deref())
+        }).await?;
+    }
+
+    info!("Successfully copied {} to {}", src_path, dest_path);
+    Ok(())
+}
+
+/// Pipe an object from `source` into a multipart upload on `backend`,
+/// aborting the upload on failure. `chunk_size` ranges are fetched
+/// `concurrent_parts` at a time and written in order, so at most that many
+/// chunks (plus the writer's own part buffers) are held in memory. The data
+/// must hash to `expected`, if given, before the upload is completed.
+async fn pipe_multipart<B, S>(backend: &B, source: &S, src: &ObjectPath, dest: &ObjectPath, size: usize, expected: Option<&str>) -> Result<()>
+where
+    B: StorageBackend + ?Sized,
+    S: StorageBackend + ?Sized,
+{
+    let chunk_size = backend.transfer_options().chunk_size.max(1);
+    let concurrency = backend.transfer_options().concurrent_parts.max(1);
+
+    let (multipart_id, mut writer) = backend.store().put_multipart(dest).await
+        .map_err(|e| {
+            error!("Error starting multipart upload to {}: {}", backend.provider_name(), e);
+            ToolError::Storage(e)
+        })?;
+
+    let mut chunks = stream::iter((0..size).step_by(chunk_size))
+        .map(|start| source.store().get_range(src, start..(start + chunk_size).min(size)))
+        .buffered(concurrency);
+
+    let result: Result<()> = async {
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = chunks.try_next().await? {
+            hasher.update(&chunk);
+            writer.write_all(&chunk).await?;
+        }
+        if let Some(expected) = expected {
+            check_sha256(src.as_ref(), expected, hex::encode(hasher.finalize()))?;
+        }
+        writer.shutdown().await?;
+        Ok(())
+    }.await;
+
+    if let Err(e) = result {
+        error!("Error copying {} to {}: {}", src, backend.provider_name(), e);
+        if let Err(abort_error) = backend.store().abort_multipart(dest, &multipart_id).await {
+            warn!("Failed to abort multipart upload of {}: {}", dest, abort_error);
+        }
+        return Err(e);
+    }
+
+    Ok(())
+}
+
+/// Suffix of the file a download is written to until it is complete
+pub(super) const PARTIAL_SUFFIX: &str = ".mytool-part";
+
+/// Where a download is written until it is complete
+pub(super) fn partial_path(local_path: &Path) -> PathBuf {
+    let mut name = local_path.file_name().unwrap_or_default().to_os_string();
+    name.push(PARTIAL_SUFFIX);
+    local_path.with_file_name(name)
+}
+
+/// Stream an object into `partial`, returning the hex SHA-256 of what was written
+async fn download_whole<B: StorageBackend + ?Sized>(backend: &B, remote: &ObjectPath, partial: &Path) -> Result<String> {
+    let mut stream = backend.store().get(remote).await
+        .map_err(|e| {
+            error!("Error downloading file from {}: {}", backend.provider_name(), e);
+            ToolError::Storage(e)
+        })?
+        .into_stream();
+
+    let mut file = tokio::fs::File::create(partial).await
+        .map_err(|e| {
+            error!("Error creating file {}: {}", partial.display(), e);
+            ToolError::Io(e)
+        })?;
+
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = stream.try_next().await
+        .map_err(|e| {
+            error!("Error downloading file from {}: {}", backend.provider_name(), e);
+            ToolError::Storage(e)
+        })? {
+        hasher.update(&chunk);
+        file.write_all(&chunk).await
+            .map_err(|e| {
+                error!("Error writing file: {}", e);
+                ToolError::Io(e)
+            })?;
+    }
+
+    file.flush().await?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Check a downloaded file against the SHA-256 stored with its object,
+/// hashing the file unless its hash was taken while streaming
+async fn verify_sha256(remote_path: &str, partial: &Path, expected: &str, streamed_hash: Option<String>) -> Result<()> {
+    let actual = match streamed_hash {
+        Some(hash) => hash,
+        None => sha256_file(partial).await?,
+    };
+    check_sha256(remote_path, expected, actual)
+}
+
+/// Fail with ToolError::ChecksumMismatch unless the data read from
+/// `remote_path` hashed to the SHA-256 stored with it
+fn check_sha256(remote_path: &str, expected: &str, actual: String) -> Result<()> {
+    if actual != expected {
+        error!("Data read from {} does not match its stored SHA-256", remote_path);
+        return Err(ToolError::ChecksumMismatch {
+            path: remote_path.to_string(),
+            algorithm: "SHA-256",
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    debug!("Verified SHA-256 of {}", remote_path);
+    Ok(())
+}
+
+/// Fetch `chunk_size` ranges concurrently into a preallocated file,
+/// skipping ranges the journal records for an unchanged object
+async fn download_ranges<B: StorageBackend + ?Sized>(backend: &B, remote: &ObjectPath, local_path: &Path, partial: &Path, source: Fingerprint) -> Result<()> {
+    let size = source.size as usize;
+    let chunk_size = backend.transfer_options().chunk_size.max(1);
+    let concurrency = backend.transfer_options().concurrent_parts.max(1);
+    let journal = backend.journal();
+    let key = local_path.to_string_lossy();
+
+    // Earlier ranges are only trusted if the partial file is still there
+    let mut done = journal.map(|j| j.downloaded_ranges(&key, &source)).unwrap_or_default();
+    let partial_size = tokio::fs::metadata(partial).await.map(|m| m.len()).ok();
+    if partial_size != Some(source.size) {
+        done.clear();
+    }
+
+    if done.is_empty() {
+        let file = tokio::fs::File::create(partial).await
+            .map_err(|e| {
+                error!("Error creating file {}: {}", partial.display(), e);
+                ToolError::Io(e)
+            })?;
+        file.set_len(size as u64).await
+            .map_err(|e| {
+                error!("Error preallocating {}: {}", partial.display(), e);
+                ToolError::Io(e)
+            })?;
+    }
+
+    let ranges: Vec<Range<usize>> = (0..size)
+        .step_by(chunk_size)
+        .map(|start| start..(start + chunk_size).min(size))
+        .filter(|range| !done.contains(&(range.start as u64..range.end as u64)))
+        .collect();
+    if done.is_empty() {
+        info!("Downloading {} in {} ranges of {} bytes", remote, ranges.len(), chunk_size);
+    } else {
+        info!("Resuming download of {}: {} of {} ranges left", remote, ranges.len(), size.div_ceil(chunk_size));
+    }
+
+    let key = key.as_ref();
+    stream::iter(ranges)
+        .map(|range| async move {
+            download_range(backend, remote, partial, range.clone()).await?;
+            match journal {
+                Some(journal) => journal.record_range(key, source, range.start as u64..range.end as u64),
+                None => Ok(()),
+            }
+        })
+        .buffer_unordered(concurrency)
+        .try_collect::<()>()
+        .await
+}
+
+async fn download_range<B: StorageBackend + ?Sized>(backend: &B, remote: &ObjectPath, partial: &Path, range: Range<usize>) -> Result<()> {
+    let data = backend.store().get_range(remote, range.clone()).await
+        .map_err(|e| {
+            error!("Error downloading bytes {:?} of {} from {}: {}", range, remote, backend.provider_name(), e);
+            ToolError::Storage(e)
+        })?;
+    if data.len() != range.len() {
+        return Err(ToolError::InvalidOperation(format!(
+            "Short read of {}: expected {} bytes at offset {}, got {}",
+            remote,
+            range.len(),
+            range.start,
+            data.len()
+        )));
+    }
+
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(partial).await
+        .map_err(|e| {
+            error!("Error opening {}: {}", partial.display(), e);
+            ToolError::Io(e)
+        })?;
+    file.seek(SeekFrom::Start(range.start as u64)).await?;
+    file.write_all(&data).await
+        .map_err(|e| {
+            error!("Error writing file: {}", e);
+            ToolError::Io(e)
+        })?;
+    file.flush().await?;
+
+    debug!("Downloaded bytes {:?} of {}", range, remote);
+    Ok(())
+}
@@ -0,0 +1,232 @@
+//! Storage URL Module
+//!
+//! Parses the locations given on the command line into a typed StorageUrl, so
+//! every command applies the same rules and malformed URLs are rejected with
+//! a clear message instead of silently addressing the wrong key.
+//!
+//! Accepted forms:
+//! - `s3://bucket/key`, `gs://bucket/key`, `az://container/key`, `mem://bucket/key`
+//! - `file:///absolute/path`
+//! - `name:key` for a remote configured under `remotes`
+//! - anything else is a local path (write `./name:x` for local paths with a colon)
+//!
+//! Keys never start or end with '/', and may not contain empty, `.` or `..`
+//! segments. A trailing slash marks the location as a directory: copying a
+//! file to `s3://bucket/dir/` writes `dir/<file name>`.
+
+use crate::config::StorageProvider;
+use crate::error::{Result, ToolError};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A location given on the command line
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageUrl {
+    Local(PathBuf),
+    Remote(RemoteUrl),
+}
+
+/// What a remote URL addresses before it is resolved against the config
+#[derive(Debug, Clone, PartialEq)]
+pub enum Authority {
+    /// `scheme://bucket/...`
+    Bucket { provider: StorageProvider, bucket: String },
+    /// `file:///...`
+    Filesystem,
+    /// `name:...`
+    Named(String),
+}
+
+/// A parsed remote location
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteUrl {
+    pub authority: Authority,
+    /// Object key, without leading or trailing slashes
+    pub key: String,
+    /// The URL ended in '/', so it names a directory
+    pub trailing_slash: bool,
+}
+
+impl RemoteUrl {
+    /// Key a file called `file_name` is copied to when this URL is the destination
+    pub fn key_for(&self, file_name: &str) -> String {
+        if self.key.is_empty() {
+            file_name.to_string()
+        } else if self.trailing_slash {
+            format!("{}/{}", self.key, file_name)
+        } else {
+            self.key.clone()
+        }
+    }
+
+    /// Last segment of the key, used as the file name of downloads
+    pub fn file_name(&self) -> &str {
+        self.key.rsplit('/').next().unwrap_or(&self.key)
+    }
+}
+
+impl FromStr for StorageUrl {
+    type Err = ToolError;
+
+    fn from_str(url: &str) -> Result<Self> {
+        if url.is_empty() {
+            return Err(ToolError::InvalidOperation("Empty path".into()));
+        }
+
+        if let Some((scheme, rest)) = url.split_once("://") {
+            let provider = StorageProvider::from_scheme(scheme).ok_or_else(|| invalid(
+                url,
+                &format!("unsupported scheme '{}://' (expected s3, gs, az, file or mem)", scheme),
+            ))?;
+
+            if provider == StorageProvider::Local {
+                let path = rest.strip_prefix('/')
+                    .ok_or_else(|| invalid(url, "file:// URLs need an absolute path, e.g. file:///data/run1"))?;
+                return parse_remote(url, Authority::Filesystem, path);
+            }
+
+            let (bucket, path) = rest.split_once('/').unwrap_or((rest, ""));
+            if bucket.is_empty() {
+                return Err(invalid(url, "missing bucket name"));
+            }
+            if !bucket.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+                return Err(invalid(url, &format!("invalid bucket name '{}'", bucket)));
+            }
+            return parse_remote(url, Authority::Bucket { provider, bucket: bucket.to_string() }, path);
+        }
+
+        match url.split_once(':') {
+            Some((name, path)) if is_remote_name(name) => {
+                parse_remote(url, Authority::Named(name.to_string()), path.trim_start_matches('/'))
+            }
+            _ => Ok(StorageUrl::Local(PathBuf::from(url))),
+        }
+    }
+}
+
+impl fmt::Display for StorageUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageUrl::Local(path) => write!(f, "{}", path.display()),
+            StorageUrl::Remote(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+impl fmt::Display for RemoteUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.authority {
+            Authority::Bucket { provider, bucket } => write!(f, "{}://{}/{}", provider.scheme(), bucket, self.key)?,
+            Authority::Filesystem => write!(f, "file:///{}", self.key)?,
+            Authority::Named(name) => write!(f, "{}:{}", name, self.key)?,
+        }
+        if self.trailing_slash && !self.key.is_empty() {
+            write!(f, "/")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_remote(url: &str, authority: Authority, path: &str) -> Result<StorageUrl> {
+    let trailing_slash = path.ends_with('/');
+    let key = path.trim_end_matches('/');
+
+    if !key.is_empty() {
+        for segment in key.split('/') {
+            match segment {
+                "" => return Err(invalid(url, "empty path segment ('//')")),
+                "." | ".." => return Err(invalid(url, "'.' and '..' are not allowed in object keys")),
+                _ => {}
+            }
+        }
+    }
+
+    Ok(StorageUrl::Remote(RemoteUrl {
+        authority,
+        key: key.to_string(),
+        trailing_slash,
+    }))
+}
+
+fn is_remote_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn invalid(url: &str, reason: &str) -> ToolError {
+    ToolError::InvalidOperation(format!("Invalid storage URL '{}': {}", url, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remote(url: &str) -> RemoteUrl {
+        match url.parse::<StorageUrl>().unwrap() {
+            StorageUrl::Remote(remote) => remote,
+            StorageUrl::Local(path) => panic!("{} parsed as local path {}", url, path.display()),
+        }
+    }
+
+    fn rejects(url: &str, reason: &str) {
+        match url.parse::<StorageUrl>() {
+            Err(ToolError::InvalidOperation(message)) => assert!(message.contains(reason), "{}: {}", url, message),
+            other => panic!("{} parsed as {:?}", url, other),
+        }
+    }
+
+    #[test]
+    fn parses_bucket_urls() {
+        let url = remote("s3://ml-artifacts/runs/exp42/model.pt");
+        assert_eq!(url.authority, Authority::Bucket { provider: StorageProvider::S3, bucket: "ml-artifacts".into() });
+        assert_eq!(url.key, "runs/exp42/model.pt");
+        assert!(!url.trailing_slash);
+
+        for (url, provider) in [("gs://b/k", StorageProvider::GCS), ("az://b/k", StorageProvider::Azure), ("mem://b/k", StorageProvider::Memory)] {
+            assert_eq!(remote(url).authority, Authority::Bucket { provider, bucket: "b".into() });
+        }
+    }
+
+    #[test]
+    fn trailing_slash_marks_a_directory() {
+        let url = remote("s3://bucket/runs/");
+        assert_eq!(url.key, "runs");
+        assert!(url.trailing_slash);
+        assert_eq!(url.key_for("model.pt"), "runs/model.pt");
+        assert_eq!(remote("s3://bucket/runs/model.pt").key_for("other.pt"), "runs/model.pt");
+        assert_eq!(remote("s3://bucket").key_for("model.pt"), "model.pt");
+        assert_eq!(url.to_string(), "s3://bucket/runs/");
+    }
+
+    #[test]
+    fn parses_file_urls_and_named_remotes() {
+        let url = remote("file:///data/run1");
+        assert_eq!(url.authority, Authority::Filesystem);
+        assert_eq!(url.key, "data/run1");
+        assert_eq!(url.to_string(), "file:///data/run1");
+
+        let url = remote("backup:/runs/exp42");
+        assert_eq!(url.authority, Authority::Named("backup".into()));
+        assert_eq!(url.key, "runs/exp42");
+        assert_eq!(url.file_name(), "exp42");
+    }
+
+    #[test]
+    fn everything_else_is_a_local_path() {
+        for path in ["./runs", "/tmp/model.pt", "runs/exp42", "./name:x"] {
+            assert_eq!(path.parse::<StorageUrl>().unwrap(), StorageUrl::Local(PathBuf::from(path)));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        rejects("", "Empty path");
+        rejects("ftp://host/file", "unsupported scheme");
+        rejects("file://relative/path", "absolute path");
+        rejects("s3:///key", "missing bucket name");
+        rejects("s3://bad bucket/key", "invalid bucket name");
+        rejects("s3://bucket/a//b", "empty path segment");
+        rejects("s3://bucket/a/../b", "are not allowed");
+        rejects("backup:./runs", "are not allowed");
+    }
+}
@@ -0,0 +1,413 @@
+//! Watch Mode Module
+//!
+//! Keeps a remote prefix in step with a local directory that is still being
+//! written to, such as the checkpoint directory of a running training job.
+//! After an initial `sync_local_to_remote`, filesystem events (inotify on
+//! Linux) mark the files they touch as pending; a pending file is uploaded
+//! once no event has touched it for the debounce interval, so files are
+//! only read after their writer has finished with them.
+//!
+//! Only the files events name are looked at:
+//! - a file that still exists is uploaded if its size or mtime differs from
+//!   what was last uploaded or found in sync
+//! - a directory that appears is walked, since files created in it before
+//!   its watch was added raise no events of their own
+//! - a file or directory that disappears is deleted from the remote when
+//!   `options.delete` is set
+//!
+//! Deletes go through the same safeguards as a sync: a round that would
+//! delete every file, or more than `max_delete`/`max_delete_percent` allow,
+//! stops the watch with an error. With `options.backup_dir`, remote files
+//! are backed up before they are overwritten and moved there instead of
+//! being deleted.
+//!
+//! Failed uploads and deletes are logged and retried after another debounce
+//! interval, so a flaky network does not stop the watch. SIGINT or SIGTERM
+//! stops it after the transfers in flight: a final sync then picks up files
+//! that were still pending, and a second signal abandons that sync. Signals
+//! during the initial sync stop the process as usual.
+
+use super::filter::Filter;
+use super::journal::Fingerprint;
+use super::sync::{back_up_object, check_deletes, delete_remote, list_files_recursively, remote_backup_dir, run_transfers};
+use super::{StorageBackend, SyncOptions};
+use crate::error::{Result, ToolError};
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing::{debug, info, error, warn};
+
+/// How often pending files are checked for having settled
+const TICK: Duration = Duration::from_millis(250);
+
+/// Local files the remote is known to hold, by path, with the version it holds
+type Known = HashMap<PathBuf, Fingerprint>;
+
+pub(super) async fn watch_local_to_remote<B: StorageBackend + ?Sized>(
+    backend: &B,
+    local_dir: &Path,
+    remote_prefix: &str,
+    options: &SyncOptions,
+    debounce: Duration,
+) -> Result<()> {
+    let backup_dir = remote_backup_dir(options, remote_prefix)?;
+
+    // Watch before the initial sync, so files written during it raise events
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        // The receiver is only dropped once the watch has stopped
+        let _ = events_tx.send(event);
+    })
+    .map_err(|e| ToolError::InvalidOperation(format!("Cannot watch {}: {}", local_dir.display(), e)))?;
+    watcher.watch(local_dir, RecursiveMode::Recursive)
+        .map_err(|e| ToolError::InvalidOperation(format!("Cannot watch {}: {}", local_dir.display(), e)))?;
+
+    backend.sync_local_to_remote(local_dir, remote_prefix, options).await?;
+    let mut known = Known::new();
+    for (path, _) in list_files_recursively(local_dir, backend.filter(), None).await? {
+        if let Ok(metadata) = tokio::fs::metadata(&path).await {
+            known.insert(path, Fingerprint::from_metadata(&metadata));
+        }
+    }
+    info!("Watching {} for changes; uploading files {}s after their last change", local_dir.display(), debounce.as_secs_f32());
+
+    // Only trapped once the initial sync is done, so it can be interrupted
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    // Paths events touched, with the time of their last event
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
+            event = events.recv() => match event {
+                Some(Ok(event)) => note_event(event, local_dir, backend.filter(), &mut pending),
+                Some(Err(e)) => warn!("Error watching {}: {}", local_dir.display(), e),
+                None => break,
+            },
+            _ = tick.tick() => {
+                let now = Instant::now();
+                let settled: Vec<PathBuf> = pending.iter()
+                    .filter(|(_, last_event)| now.duration_since(**last_event) >= debounce)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                if settled.is_empty() {
+                    continue;
+                }
+                for path in &settled {
+                    pending.remove(path);
+                }
+                for path in apply(backend, local_dir, remote_prefix, options, backup_dir, settled, &mut known).await? {
+                    pending.insert(path, Instant::now());
+                }
+            }
+        }
+    }
+
+    drop(watcher);
+    info!("Stopping the watch of {}; {} files still pending, signal again to skip the final sync", local_dir.display(), pending.len());
+    tokio::select! {
+        result = backend.sync_local_to_remote(local_dir, remote_prefix, options) => result,
+        _ = interrupt.recv() => Err(ToolError::InvalidOperation("Interrupted during the final sync".to_string())),
+        _ = terminate.recv() => Err(ToolError::InvalidOperation("Terminated during the final sync".to_string())),
+    }
+}
+
+/// Mark the paths an event touched as pending
+fn note_event(event: Event, local_dir: &Path, filter: Option<&Filter>, pending: &mut HashMap<PathBuf, Instant>) {
+    match event.kind {
+        // Reads, including those of our own uploads, change nothing
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => {}
+        EventKind::Access(_) => return,
+        _ => {}
+    }
+    let now = Instant::now();
+    for path in event.paths {
+        let selected = match path.strip_prefix(local_dir) {
+            Ok(relative) => {
+                let relative = relative.to_string_lossy();
+                // Whether it is a file the filter selects can only be told
+                // once it has settled; excluded directories are left out now
+                !relative.is_empty() && filter.is_none_or(|filter| !filter.excludes_dir(&relative))
+            }
+            Err(_) => false,
+        };
+        if selected {
+            debug!("{:?} {}", event.kind, path.display());
+            pending.insert(path, now);
+        }
+    }
+}
+
+/// Bring the remote in step with the settled paths; returns the paths whose
+/// transfer or delete failed, to retry later. Fails only when the deletes
+/// are refused by the safeguards.
+async fn apply<B: StorageBackend + ?Sized>(
+    backend: &B,
+    local_dir: &Path,
+    remote_prefix: &str,
+    options: &SyncOptions,
+    backup_dir: Option<&str>,
+    settled: Vec<PathBuf>,
+    known: &mut Known,
+) -> Result<Vec<PathBuf>> {
+    let mut uploads = Vec::new();
+    let mut deletes = Vec::new();
+    for path in settled {
+        match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                // Files created before the directory's watch was added; the
+                // filter is applied below, relative to local_dir
+                match list_files_recursively(&path, None, None).await {
+                    Ok(files) => {
+                        for (file, _) in files {
+                            if let Ok(metadata) = tokio::fs::metadata(&file).await {
+                                uploads.push((file, Fingerprint::from_metadata(&metadata)));
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Cannot list {}: {}", path.display(), e),
+                }
+            }
+            Ok(metadata) => uploads.push((path, Fingerprint::from_metadata(&metadata))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // A file, or a directory with everything below it; deleted
+                // files stay known until the remote no longer holds them
+                let gone: Vec<PathBuf> = known.keys().filter(|known| known.starts_with(&path)).cloned().collect();
+                for path in gone {
+                    if options.delete {
+                        deletes.push(path);
+                    } else {
+                        known.remove(&path);
+                    }
+                }
+            }
+            Err(e) => warn!("Cannot read {}: {}", path.display(), e),
+        }
+    }
+    deletes.sort();
+    deletes.dedup();
+
+    // A new directory's files may also have been named by their own events
+    uploads.sort_by(|(a, _), (b, _)| a.cmp(b));
+    uploads.dedup_by(|(a, _), (b, _)| a == b);
+    let filter = backend.filter();
+    uploads.retain(|(path, source)| {
+        known.get(path) != Some(source)
+            && filter.is_none_or(|filter| filter.matches(&path.strip_prefix(local_dir).unwrap_or(path).to_string_lossy()))
+    });
+    let key = |path: &Path| {
+        let relative = path.strip_prefix(local_dir).unwrap_or(path).to_string_lossy().to_string();
+        if remote_prefix.is_empty() {
+            relative
+        } else {
+            format!("{}/{}", remote_prefix.trim_matches('/'), relative)
+        }
+    };
+
+    // Checked before anything is transferred, as a sync does
+    check_deletes(None, options, &local_dir.display().to_string(), known.len() - deletes.len(), known.len(), deletes.len())?;
+
+    let mut failed = Vec::new();
+    let limit = backend.transfer_options().concurrent_uploads;
+    let items = uploads.iter()
+        .map(|(path, _)| (path.display().to_string(), (path.clone(), known.contains_key(path))))
+        .collect();
+    if let Err(e) = run_transfers(limit, items, |(path, overwrite)| async move {
+        let remote_path = key(&path);
+        if let (Some(backup_dir), true) = (backup_dir, overwrite) {
+            back_up_object(backend, backup_dir, remote_prefix, &remote_path, false).await?;
+        }
+        backend.upload_file(&path, &remote_path).await
+    }).await {
+        failed.extend(failed_paths(&e));
+        error!("Uploads will be retried: {}", e);
+    }
+    for (path, source) in uploads {
+        if !failed.contains(&path) {
+            known.insert(path, source);
+        }
+    }
+
+    let mut deleted: HashMap<String, PathBuf> = deletes.into_iter().map(|path| (key(&path), path)).collect();
+    if let Err(e) = delete_remote(backend, backup_dir, remote_prefix, deleted.keys().cloned().collect()).await {
+        error!("Deletes will be retried: {}", e);
+        match &e {
+            ToolError::Transfers { failures, .. } => {
+                for (key, _) in failures {
+                    if let Some(path) = deleted.remove(key) {
+                        failed.push(path);
+                    }
+                }
+            }
+            _ => failed.extend(deleted.drain().map(|(_, path)| path)),
+        }
+    }
+    for path in deleted.values() {
+        known.remove(path);
+    }
+    Ok(failed)
+}
+
+fn failed_paths(error: &ToolError) -> Vec<PathBuf> {
+    match error {
+        ToolError::Transfers { failures, .. } => failures.iter().map(|(label, _)| PathBuf::from(label)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, StorageConfig};
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::TransferContext;
+    use notify::event::{CreateKind, ModifyKind, RemoveKind};
+
+    fn memory() -> MemoryStorage {
+        MemoryStorage::new(&StorageConfig::default(), &Config::default().transfer_options, &TransferContext::default())
+    }
+
+    fn event(kind: EventKind, path: &Path) -> Event {
+        Event::new(kind).add_path(path.to_path_buf())
+    }
+
+    fn write(dir: &Path, path: &str, content: &[u8]) -> PathBuf {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// Sizes of the objects under `runs`, by key
+    async fn remote_sizes(remote: &MemoryStorage) -> Vec<(String, u64)> {
+        let mut sizes: Vec<_> = remote.list_files_with_metadata("runs").await.unwrap()
+            .into_iter()
+            .map(|(key, (size, _))| (key, size))
+            .collect();
+        sizes.sort();
+        sizes
+    }
+
+    /// A remote under `runs` holding the files at `paths`, all known
+    async fn uploaded(dir: &Path, paths: &[&str]) -> (MemoryStorage, Known) {
+        let remote = memory();
+        let settled = paths.iter().map(|path| write(dir, path, path.as_bytes())).collect();
+        let mut known = Known::new();
+        let failed = apply(&remote, dir, "runs", &SyncOptions::default(), None, settled, &mut known).await.unwrap();
+        assert!(failed.is_empty());
+        (remote, known)
+    }
+
+    #[test]
+    fn events_mark_the_paths_they_touch_as_pending() {
+        let dir = Path::new("/data/run");
+        let filter = Filter::new(&[], &["logs".to_string()], None).unwrap();
+        let mut pending = HashMap::new();
+        let mut note = |kind, path: &str| note_event(event(kind, Path::new(path)), dir, filter.as_ref(), &mut pending);
+
+        note(EventKind::Create(CreateKind::File), "/data/run/a.bin");
+        note(EventKind::Modify(ModifyKind::Any), "/data/run/sub/b.bin");
+        note(EventKind::Access(AccessKind::Close(AccessMode::Write)), "/data/run/c.bin");
+        note(EventKind::Remove(RemoveKind::File), "/data/run/d.bin");
+        // Reads, the watched directory itself, paths outside it and
+        // excluded directories are not
+        note(EventKind::Access(AccessKind::Close(AccessMode::Read)), "/data/run/e.bin");
+        note(EventKind::Access(AccessKind::Any), "/data/run/f.bin");
+        note(EventKind::Modify(ModifyKind::Any), "/data/run");
+        note(EventKind::Create(CreateKind::File), "/data/other/g.bin");
+        note(EventKind::Create(CreateKind::Folder), "/data/run/logs");
+
+        let mut paths: Vec<_> = pending.into_keys().collect();
+        paths.sort();
+        assert_eq!(paths, ["/data/run/a.bin", "/data/run/c.bin", "/data/run/d.bin", "/data/run/sub/b.bin"].map(PathBuf::from));
+    }
+
+    #[tokio::test]
+    async fn created_and_modified_files_are_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, mut known) = uploaded(dir.path(), &["a.txt"]).await;
+        // Files of a new directory are found by walking it
+        write(dir.path(), "new/b.txt", b"bb");
+        write(dir.path(), "new/deeper/c.txt", b"ccc");
+        let failed = apply(&remote, dir.path(), "runs", &SyncOptions::default(), None, vec![dir.path().join("new")], &mut known).await.unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(remote_sizes(&remote).await, [("runs/a.txt".to_string(), 5), ("runs/new/b.txt".to_string(), 2), ("runs/new/deeper/c.txt".to_string(), 3)]);
+
+        let modified = write(dir.path(), "a.txt", b"rewritten");
+        apply(&remote, dir.path(), "runs", &SyncOptions::default(), None, vec![modified.clone()], &mut known).await.unwrap();
+        assert_eq!(remote_sizes(&remote).await[0], ("runs/a.txt".to_string(), 9));
+        assert_eq!(known.len(), 3);
+
+        // A file the remote already holds is not uploaded again
+        remote.delete_objects(&["runs/a.txt".to_string()]).await.unwrap();
+        apply(&remote, dir.path(), "runs", &SyncOptions::default(), None, vec![modified], &mut known).await.unwrap();
+        assert_eq!(remote_sizes(&remote).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn removed_files_are_deleted_only_with_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, mut known) = uploaded(dir.path(), &["a.txt", "b.txt"]).await;
+        std::fs::remove_file(dir.path().join("a.txt")).unwrap();
+
+        apply(&remote, dir.path(), "runs", &SyncOptions::default(), None, vec![dir.path().join("a.txt")], &mut known).await.unwrap();
+        assert_eq!(remote_sizes(&remote).await.len(), 2);
+        assert!(!known.contains_key(&dir.path().join("a.txt")));
+    }
+
+    #[tokio::test]
+    async fn removed_directories_are_deleted_within_max_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, mut known) = uploaded(dir.path(), &["keep.txt", "old/a.txt", "old/b.txt"]).await;
+        std::fs::remove_dir_all(dir.path().join("old")).unwrap();
+        let settled = || vec![dir.path().join("old")];
+
+        let options = SyncOptions { delete: true, max_delete: Some(1), ..Default::default() };
+        let result = apply(&remote, dir.path(), "runs", &options, None, settled(), &mut known).await;
+        assert!(matches!(result, Err(ToolError::InvalidOperation(message)) if message.contains("--max-delete is 1")));
+        assert_eq!(remote_sizes(&remote).await.len(), 3);
+        assert_eq!(known.len(), 3);
+
+        let options = SyncOptions { delete: true, max_delete: Some(2), ..Default::default() };
+        let failed = apply(&remote, dir.path(), "runs", &options, None, settled(), &mut known).await.unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(remote_sizes(&remote).await, [("runs/keep.txt".to_string(), 8)]);
+        assert_eq!(known.into_keys().collect::<Vec<_>>(), [dir.path().join("keep.txt")]);
+    }
+
+    #[tokio::test]
+    async fn removed_files_are_moved_to_the_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, mut known) = uploaded(dir.path(), &["a.txt", "b.txt"]).await;
+        std::fs::remove_file(dir.path().join("a.txt")).unwrap();
+
+        let options = SyncOptions { delete: true, backup_dir: Some("backup".to_string()), ..Default::default() };
+        let backup_dir = remote_backup_dir(&options, "runs").unwrap();
+        let failed = apply(&remote, dir.path(), "runs", &options, backup_dir, vec![dir.path().join("a.txt")], &mut known).await.unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(remote_sizes(&remote).await, [("runs/b.txt".to_string(), 5)]);
+        assert_eq!(remote.list_objects("backup").await.unwrap(), ["backup/a.txt"]);
+    }
+
+    #[tokio::test]
+    async fn failed_deletes_are_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let (remote, mut known) = uploaded(dir.path(), &["a.txt", "b.txt"]).await;
+        // Gone from the remote too, so moving it to the backup dir fails
+        std::fs::remove_file(dir.path().join("a.txt")).unwrap();
+        remote.delete_objects(&["runs/a.txt".to_string()]).await.unwrap();
+
+        let options = SyncOptions { delete: true, backup_dir: Some("backup".to_string()), ..Default::default() };
+        let backup_dir = remote_backup_dir(&options, "runs").unwrap();
+        let failed = apply(&remote, dir.path(), "runs", &options, backup_dir, vec![dir.path().join("a.txt")], &mut known).await.unwrap();
+        assert_eq!(failed, [dir.path().join("a.txt")]);
+        assert!(known.contains_key(&dir.path().join("a.txt")));
+    }
+}